@@ -0,0 +1,291 @@
+use crate::api::with_server;
+use crate::log;
+use crate::message::{UserMessage, UserMessageError};
+use crate::server::Server;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use warp::http::{header, StatusCode};
+use warp::reply::{self, Response};
+use warp::{reject, Filter, Rejection, Reply};
+
+/// Changes to a lobby. Missing fields are left as they are
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct LobbyEdit {
+    lobby_name: Option<String>,
+    public_lobby: Option<bool>,
+}
+
+/// New filter of the log output, like `warn,rtc_lobby=debug`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct LogFilter {
+    filter: String,
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl reject::Reject for Unauthorized {}
+
+/// `/admin/lobbies`: lists every lobby, and closes or edits them.
+/// `/admin/log-filter`: replaces the filter of the log output.
+///
+/// Requests need an `Authorization: Bearer <token>` header with the admin token.
+/// Without an admin token in the options the routes are not served.
+pub fn api_admin(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let authorized = warp::path("admin")
+        .and(with_server(server))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |server: Arc<Server>, authorization: Option<String>| async move {
+                if server.options().admin_token.is_none() {
+                    return Err(reject::not_found());
+                }
+
+                let token = authorization
+                    .as_deref()
+                    .and_then(|authorization| authorization.strip_prefix("Bearer "));
+                match token {
+                    Some(token) if server.is_admin_token(token) => Ok(server),
+                    _ => Err(reject::custom(Unauthorized)),
+                }
+            },
+        );
+
+    let list = authorized
+        .clone()
+        .and(warp::path!("lobbies"))
+        .and(warp::get())
+        .then(|server: Arc<Server>| async move {
+            let lobbies = server.lobbies_info().await;
+            reply::json(&json!({ "lobbies": lobbies })).into_response()
+        });
+
+    let close = authorized
+        .clone()
+        .and(warp::path!("lobbies" / String))
+        .and(warp::delete())
+        .then(|server: Arc<Server>, lobby_name: String| async move {
+            let Some(lobby_name) = decode(&lobby_name) else {
+                return error_response(StatusCode::BAD_REQUEST, "invalidLobbyName");
+            };
+
+            tracing::info!(%lobby_name, "Admin closed lobby");
+            match server.close_lobby(&lobby_name).await {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(()) => error_response(StatusCode::NOT_FOUND, "lobbyNotFound"),
+            }
+        });
+
+    let edit = authorized
+        .clone()
+        .and(warp::path!("lobbies" / String))
+        .and(warp::patch())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .then(
+            |server: Arc<Server>, lobby_name: String, edit: LobbyEdit| async move {
+                let Some(lobby_name) = decode(&lobby_name) else {
+                    return error_response(StatusCode::BAD_REQUEST, "invalidLobbyName");
+                };
+
+                tracing::info!(%lobby_name, ?edit, "Admin edited lobby");
+                let edited = server.edit_lobby(&lobby_name, edit.lobby_name, edit.public_lobby);
+                match edited.await {
+                    Ok(details) => reply::json(&details).into_response(),
+                    Err(UserMessage::Error {
+                        error_type: UserMessageError::LobbyAlreadyExists,
+                        ..
+                    }) => error_response(StatusCode::CONFLICT, "lobbyAlreadyExists"),
+                    Err(UserMessage::Error {
+                        error_type: UserMessageError::InvalidField,
+                        message,
+                        ..
+                    }) => error_with_message(StatusCode::BAD_REQUEST, "invalidLobbyName", &message),
+                    Err(_) => error_response(StatusCode::NOT_FOUND, "lobbyNotFound"),
+                }
+            },
+        );
+
+    let log_filter = authorized
+        .and(warp::path!("log-filter"))
+        .and(warp::put())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json())
+        .then(|_server: Arc<Server>, log_filter: LogFilter| async move {
+            if let Err(reason) = log::parse_filter(&log_filter.filter) {
+                return error_with_message(StatusCode::BAD_REQUEST, "invalidFilter", &reason);
+            }
+
+            match log::set_filter(&log_filter.filter) {
+                Ok(()) => {
+                    tracing::info!(filter = %log_filter.filter, "Admin changed the log filter");
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Err(reason) => {
+                    error_with_message(StatusCode::CONFLICT, "logNotInitialized", &reason)
+                }
+            }
+        });
+
+    list.or(close)
+        .unify()
+        .or(edit)
+        .unify()
+        .or(log_filter)
+        .unify()
+        .recover(|rejection: Rejection| async move {
+            if rejection.find::<Unauthorized>().is_some() {
+                Ok(unauthorized())
+            } else {
+                Err(rejection)
+            }
+        })
+        .unify()
+}
+
+fn decode(lobby_name: &str) -> Option<String> {
+    let lobby_name = percent_decode_str(lobby_name).decode_utf8().ok()?;
+    Some(lobby_name.into_owned())
+}
+
+fn error_response(status: StatusCode, error: &str) -> Response {
+    reply::with_status(reply::json(&json!({ "error": error })), status).into_response()
+}
+
+fn error_with_message(status: StatusCode, error: &str, message: &str) -> Response {
+    let body = json!({ "error": error, "message": message });
+    reply::with_status(reply::json(&body), status).into_response()
+}
+
+fn unauthorized() -> Response {
+    let response = error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    reply::with_header(response, header::WWW_AUTHENTICATE, "Bearer").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::server::tests::{create_lobby, receive};
+    use crate::server::{Server, ServerOptions};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time;
+    use warp::http::{header, Response, StatusCode};
+    use warp::hyper::body::Bytes;
+
+    fn admin_server() -> Arc<Server> {
+        Arc::new(Server::new(ServerOptions {
+            admin_token: Some("admin".to_string()),
+            ..ServerOptions::default()
+        }))
+    }
+
+    async fn admin(
+        server: &Arc<Server>,
+        method: &str,
+        path: &str,
+        body: Option<Value>,
+    ) -> Response<Bytes> {
+        let mut request = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", "Bearer admin");
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        request.reply(&routes(server.clone())).await
+    }
+
+    fn json_body(response: &Response<Bytes>) -> Value {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_the_token() {
+        let server = Arc::new(Server::default());
+        let response = warp::test::request()
+            .path("/admin/lobbies")
+            .header("authorization", "Bearer ")
+            .reply(&routes(server))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let server = admin_server();
+        for authorization in [None, Some("Bearer other"), Some("admin")] {
+            let mut request = warp::test::request().path("/admin/lobbies");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.reply(&routes(server.clone())).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+            assert_eq!(json_body(&response)["error"], "unauthorized");
+        }
+
+        let response = admin(&server, "GET", "/admin/lobbies", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response), json!({ "lobbies": [] }));
+    }
+
+    #[tokio::test]
+    async fn admins_list_and_close_lobbies() {
+        let server = admin_server();
+        let (mut host, _) = create_lobby(&server, "Two Words").await;
+
+        let response = admin(&server, "GET", "/admin/lobbies", None).await;
+        let lobbies = &json_body(&response)["lobbies"];
+        assert_eq!(lobbies[0]["details"]["lobbyName"], "Two Words");
+        assert_eq!(lobbies[0]["pendingJoins"], 0);
+        assert_eq!(lobbies[0]["hostAddress"], "127.0.0.1");
+
+        let response = admin(&server, "DELETE", "/admin/lobbies/Two%20Words", None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let closed = time::timeout(Duration::from_secs(5), host.recv_closed()).await;
+        assert!(closed.is_ok(), "the host WebSocket stays open");
+        assert!(server.lobbies_info().await.is_empty());
+
+        let response = admin(&server, "DELETE", "/admin/lobbies/Two%20Words", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(&response)["error"], "lobbyNotFound");
+    }
+
+    #[tokio::test]
+    async fn admins_rename_lobbies_and_change_whether_they_are_public() {
+        let server = admin_server();
+        let (mut host, _) = create_lobby(&server, "Before").await;
+        let (_other, _) = create_lobby(&server, "Taken").await;
+
+        let edit = json!({ "lobbyName": "After", "publicLobby": false });
+        let response = admin(&server, "PATCH", "/admin/lobbies/Before", Some(edit)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let details = json_body(&response);
+        assert_eq!(
+            (&details["lobbyName"], &details["publicLobby"]),
+            (&json!("After"), &json!(false))
+        );
+        let details = receive(&mut host).await;
+        assert_eq!(details["type"], "lobby-details");
+        assert_eq!(details["details"]["lobbyName"], "After");
+
+        let edit = json!({ "lobbyName": "TAKEN" });
+        let response = admin(&server, "PATCH", "/admin/lobbies/After", Some(edit)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(json_body(&response)["error"], "lobbyAlreadyExists");
+
+        let edit = json!({ "lobbyName": " " });
+        let response = admin(&server, "PATCH", "/admin/lobbies/After", Some(edit)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(&response)["error"], "invalidLobbyName");
+
+        let edit = json!({ "publicLobby": true });
+        let response = admin(&server, "PATCH", "/admin/lobbies/Missing", Some(edit)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,632 @@
+use crate::admin::api_admin;
+use crate::message::*;
+use crate::metrics::Role;
+use crate::protocol::{Protocol, PROTOCOL_HEADER};
+use crate::server::*;
+use crate::tls::PeerAddress;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::*;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::field::{self, Empty};
+use tracing::Instrument;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{reject, reply, Filter, Rejection, Reply};
+
+/// `POST /api/client`: join requests and lobby listings.
+/// As a WebSocket it also relays the ice candidates of a join.
+pub fn api_client(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let client_body_limit = server.options().client_body_limit;
+
+    let api_client_post =
+        warp::post()
+            .and(warp::body::content_length_limit(client_body_limit))
+            .and(warp::body::bytes())
+            .and(warp::header::optional::<String>(PROTOCOL_HEADER))
+            .and(with_address())
+            .and(with_server(server.clone()))
+            .and_then(
+                |body: Bytes,
+                 version: Option<String>,
+                 address: Option<IpAddr>,
+                 server: Arc<Server>| async move {
+                    handle_client(&server, &body, version, address).await
+                },
+            )
+            .with(cors(server.options()));
+
+    let api_client_ws = warp::ws()
+        .and(warp::header::optional("origin"))
+        .and(with_address())
+        .and(with_server(server))
+        .map(
+            move |ws: Ws, origin: Option<String>, address: Option<IpAddr>, server: Arc<Server>| {
+                if !is_origin_allowed(&server, origin.as_deref()) {
+                    return forbidden_origin(origin.as_deref().unwrap_or_default());
+                }
+
+                let span = tracing::info_span!(
+                    "client",
+                    address = address.map(field::display),
+                    lobby_name = Empty,
+                    id = Empty,
+                );
+                ws.max_message_size(client_body_limit as usize)
+                    .on_upgrade(move |client| {
+                        async move { handle_client_ws(&server, client, address).await }
+                            .instrument(span)
+                    })
+                    .into_response()
+            },
+        );
+
+    warp::path!("api" / "client").and(api_client_post.or(api_client_ws))
+}
+
+/// `GET /api/host`: WebSocket that creates and holds a lobby, or stands by to host one
+pub fn api_host(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "host")
+        .and(warp::ws())
+        .and(warp::header::optional("origin"))
+        .and(with_address())
+        .and(with_server(server))
+        .map(
+            |ws: Ws, origin: Option<String>, address: Option<IpAddr>, server: Arc<Server>| {
+                if !is_origin_allowed(&server, origin.as_deref()) {
+                    return forbidden_origin(origin.as_deref().unwrap_or_default());
+                }
+
+                let span = tracing::info_span!(
+                    "host",
+                    address = address.map(field::display),
+                    lobby_name = Empty,
+                );
+                let host_message_limit = server.options().host_message_limit;
+                ws.max_message_size(host_message_limit as usize)
+                    .on_upgrade(move |host| {
+                        async move { handle_host(&server, host, address).await }.instrument(span)
+                    })
+                    .into_response()
+            },
+        )
+}
+
+/// `GET /metrics`: metrics in the Prometheus text format
+pub fn api_metrics(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(with_server(server))
+        .then(|server: Arc<Server>| async move {
+            let metrics = server.metrics().await;
+            reply::with_header(metrics, "content-type", prometheus::TEXT_FORMAT)
+        })
+}
+
+/// `GET /healthz`: the server is running.
+/// `GET /readyz`: the server accepts new lobbies and joins.
+pub fn api_health(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let healthz = warp::path!("healthz").map(|| "ok");
+
+    let readyz = warp::path!("readyz")
+        .and(with_server(server))
+        .map(|server: Arc<Server>| {
+            if server.is_shutting_down() {
+                reply::with_status("shutting down", StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                reply::with_status("ok", StatusCode::OK)
+            }
+        });
+
+    warp::get().and(healthz.or(readyz))
+}
+
+/// `/api/client`, `/api/host`, `/metrics`, `/healthz`, `/readyz` and `/admin`
+pub fn routes(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    api_client(server.clone())
+        .or(api_host(server.clone()))
+        .or(api_metrics(server.clone()))
+        .or(api_health(server.clone()))
+        .or(api_admin(server))
+}
+
+pub(crate) fn with_server(
+    server: Arc<Server>,
+) -> impl Filter<Extract = (Arc<Server>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || server.clone())
+}
+
+fn cors(options: &ServerOptions) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(options.allowed_methods.iter().map(String::as_str))
+        .allow_headers(options.allowed_headers.iter().map(String::as_str));
+
+    match &options.allowed_origins {
+        Some(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+        None => cors.allow_any_origin(),
+    }
+}
+
+/// Browsers send the origin of the page that opens a WebSocket.
+/// Other programs usually send none, and are not restricted.
+fn is_origin_allowed(server: &Server, origin: Option<&str>) -> bool {
+    match (&server.options().allowed_origins, origin) {
+        (Some(allowed_origins), Some(origin)) => {
+            allowed_origins.iter().any(|allowed| allowed == origin)
+        }
+        _ => true,
+    }
+}
+
+fn forbidden_origin(origin: &str) -> reply::Response {
+    tracing::warn!(origin, "WebSocket opened from a forbidden origin");
+    reply::with_status("Origin not allowed", StatusCode::FORBIDDEN).into_response()
+}
+
+/// IP address of the peer, used for the rate limits
+fn with_address(
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::addr::remote().and(warp::ext::optional()).map(
+        |address: Option<SocketAddr>, peer: Option<PeerAddress>| {
+            address
+                .map(|address| address.ip())
+                .or(peer.map(|peer| peer.0))
+        },
+    )
+}
+
+async fn handle_host(server: &Server, host: WebSocket, address: Option<IpAddr>) {
+    tracing::info!("Host connected");
+
+    let (mut sender, mut receiver) = host.split();
+
+    let create_lobby_timeout = server.options().create_lobby_timeout;
+    let handshake = handshake(server, &mut sender, &mut receiver, Role::Host);
+    let Ok(handshake) = time::timeout(create_lobby_timeout, handshake).await else {
+        tracing::warn!(
+            "Host did not send a create-lobby, resume-lobby or standby-host message in time"
+        );
+        return;
+    };
+
+    let Ok((protocol, create_message)) = handshake else {
+        return;
+    };
+    let Some(create_message) = create_message else {
+        tracing::warn!(
+            "Host should have sent a create-lobby, resume-lobby or standby-host message"
+        );
+        let _ = server
+            .send(&mut sender, &protocol, UserMessageError::InvalidMessage)
+            .await;
+        return;
+    };
+
+    let lobby = match create_message {
+        UserMessage::ResumeLobby { .. } => {
+            server
+                .resume_lobby_from_message(create_message, sender, protocol, address)
+                .await
+        }
+        UserMessage::StandbyHost { .. } => {
+            server
+                .standby_from_message(create_message, sender, protocol, address)
+                .await
+        }
+        _ => {
+            server
+                .create_lobby_from_message(create_message, sender, protocol, address)
+                .await
+        }
+    };
+    let Ok(lobby) = lobby else {
+        tracing::warn!("Host could not create, resume or stand by for the lobby");
+        return;
+    };
+
+    let span = tracing::Span::current();
+    let mut lobby_name = lobby.name();
+    span.record("lobby_name", field::display(&lobby_name));
+
+    while let Some(message) = UserMessage::receive(receiver.next().await) {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                tracing::warn!(?error, "Received an invalid message from host");
+                server.send_error_to_host(&lobby, error).await;
+                continue;
+            }
+        };
+        tracing::info!(
+            message_type = message.type_name(),
+            "Received message from host"
+        );
+        server.message_received(Role::Host, &message);
+        if server.handle_host_message(&lobby, message).await.is_err() {
+            break;
+        }
+
+        // The host or an admin may have renamed the lobby
+        if lobby.name() != lobby_name {
+            lobby_name = lobby.name();
+            span.record("lobby_name", field::display(&lobby_name));
+        }
+    }
+
+    server.close_host_lobby(&lobby).await;
+}
+
+/// Reads the first message of a WebSocket. If it is a hello, it is answered and the
+/// message after it is returned. Connections without a hello speak the legacy protocol.
+/// Fails if the version of the hello is not supported, or a message can not be read
+async fn handshake(
+    server: &Server,
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    role: Role,
+) -> Result<(Protocol, Option<UserMessage>), ()> {
+    let mut message = receive(server, sender, receiver, role, &Protocol::legacy()).await?;
+
+    let Some(UserMessage::Hello {
+        version,
+        features,
+        request_id,
+    }) = message
+    else {
+        return Ok((Protocol::legacy(), message));
+    };
+
+    let protocol = match Protocol::negotiate(version, &features) {
+        Ok(protocol) => protocol,
+        Err(error) => {
+            tracing::info!(version, "Unsupported protocol version");
+            let _ = server.send(sender, &Protocol::legacy(), error).await;
+            return Err(());
+        }
+    };
+    tracing::info!(version, ?features, "Agreed on the protocol");
+    let hello = protocol.hello().with_request_id(request_id);
+    server.send(sender, &protocol, hello).await?;
+
+    message = receive(server, sender, receiver, role, &protocol).await?;
+    Ok((protocol, message))
+}
+
+/// Reads a message of the handshake, and answers it with an error if it is invalid
+async fn receive(
+    server: &Server,
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    role: Role,
+    protocol: &Protocol,
+) -> Result<Option<UserMessage>, ()> {
+    match UserMessage::receive(receiver.next().await) {
+        Some(Ok(message)) => {
+            server.message_received(role, &message);
+            Ok(Some(message))
+        }
+        Some(Err(error)) => {
+            tracing::warn!(?error, "Received an invalid message");
+            let _ = server.send(sender, protocol, error).await;
+            Err(())
+        }
+        None => Ok(None),
+    }
+}
+
+async fn handle_client(
+    server: &Server,
+    body: &[u8],
+    version: Option<String>,
+    address: Option<IpAddr>,
+) -> Result<reply::Json, reject::Rejection> {
+    let Ok(version) = version
+        .map(|version| version.trim().parse::<u32>())
+        .transpose()
+    else {
+        tracing::info!("Received a request with an invalid protocol version");
+        let error = UserMessageError::UnsupportedVersion.into();
+        return Ok(server.reply(&Protocol::legacy(), &error));
+    };
+
+    let message = match UserMessage::parse(body) {
+        Ok(message) => message,
+        Err(error) => {
+            tracing::info!(?error, "Received an invalid request");
+            // The version of the header is only used to pick the format of the error
+            let protocol = version
+                .and_then(|version| Protocol::negotiate(version, &[]).ok())
+                .unwrap_or_default();
+            return Ok(server.reply(&protocol, &error.into()));
+        }
+    };
+    server.message_received(Role::Client, &message);
+
+    // Each request says the version it speaks in a header, so a hello only asks
+    // which version and features the server agrees on
+    let negotiated = match &message {
+        UserMessage::Hello {
+            version, features, ..
+        } => Protocol::negotiate(*version, features),
+        _ => version.map_or(Ok(Protocol::legacy()), |version| {
+            Protocol::negotiate(version, &[])
+        }),
+    };
+    let protocol = match negotiated {
+        Ok(protocol) => protocol,
+        Err(error) => return Ok(server.reply(&Protocol::legacy(), &error.into())),
+    };
+    if let UserMessage::Hello { .. } = message {
+        return Ok(server.reply(&protocol, &protocol.hello()));
+    }
+
+    let span = tracing::info_span!("client", address = address.map(field::display));
+    let response = server
+        .handle_user_message(message, address)
+        .instrument(span)
+        .await;
+    Ok(server.reply(&protocol, &response))
+}
+
+/// The client sends a join-request, and after it, ice candidates
+/// are exchanged with the host until the WebSocket is closed.
+async fn handle_client_ws(server: &Server, client: WebSocket, address: Option<IpAddr>) {
+    tracing::info!("Client connected through a WebSocket");
+
+    let (mut sender, mut receiver) = client.split();
+
+    let idle_timeout = server.options().client_idle_timeout;
+    let handshake = handshake(server, &mut sender, &mut receiver, Role::Client);
+    let Ok(handshake) = time::timeout(idle_timeout, handshake).await else {
+        tracing::warn!("Client did not send a join-request message in time");
+        return;
+    };
+    let Ok((protocol, message)) = handshake else {
+        return;
+    };
+
+    let Some(UserMessage::JoinRequest {
+        lobby_name,
+        offer,
+        password,
+        ..
+    }) = message
+    else {
+        tracing::warn!("Client should have sent a join-request message");
+        let _ = server
+            .send(&mut sender, &protocol, UserMessageError::InvalidMessage)
+            .await;
+        return;
+    };
+
+    if let Err(error) = server.check_rate_limit(address, RateLimitedAction::JoinRequest) {
+        let _ = server.send(&mut sender, &protocol, error).await;
+        return;
+    }
+
+    let (ice_sender, mut ice_receiver) = mpsc::unbounded_channel();
+    let pending_join = match server
+        .request_join(lobby_name, offer, password, Some(ice_sender))
+        .await
+    {
+        Ok(pending_join) => pending_join,
+        Err(error) => {
+            let _ = server.send(&mut sender, &protocol, error).await;
+            return;
+        }
+    };
+
+    // The lobby can be renamed while the client joins it
+    let lobby = pending_join.lobby.clone();
+    let id = pending_join.id;
+    tracing::Span::current()
+        .record("lobby_name", field::display(&lobby.name()))
+        .record("id", id);
+
+    let answer = server.wait_join_answer(pending_join);
+    tokio::pin!(answer);
+    let mut answered = false;
+    // Only counts once the host answered, the join timeout applies before
+    let idle = time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    loop {
+        tokio::select! {
+            () = &mut idle, if answered => {
+                tracing::info!("Client WebSocket stayed silent, closing it");
+                break;
+            }
+            answer = &mut answer, if !answered => {
+                answered = true;
+                idle.as_mut().reset(time::Instant::now() + idle_timeout);
+                let invited = matches!(answer, UserMessage::JoinInvitation { .. });
+                if server.send(&mut sender, &protocol, answer).await.is_err() || !invited {
+                    break;
+                }
+            }
+            Some(message) = ice_receiver.recv() => {
+                if server.send(&mut sender, &protocol, message).await.is_err() {
+                    break;
+                }
+                idle.as_mut().reset(time::Instant::now() + idle_timeout);
+            }
+            message = receiver.next() => {
+                idle.as_mut().reset(time::Instant::now() + idle_timeout);
+                let message = match UserMessage::receive(message) {
+                    Some(Ok(message)) => message,
+                    Some(Err(error)) => {
+                        tracing::warn!(?error, "Received an invalid message from client");
+                        let _ = server.send(&mut sender, &protocol, error).await;
+                        continue;
+                    }
+                    None => break,
+                };
+                server.message_received(Role::Client, &message);
+
+                if let UserMessage::IceCandidate { candidate, .. } = message {
+                    let relayed = server.send_ice_candidate_to_host(&lobby, id, candidate);
+                    if let Err(error) = relayed.await {
+                        let _ = server.send(&mut sender, &protocol, error).await;
+                        break;
+                    }
+                } else {
+                    tracing::warn!("Client should only send ice candidates after joining");
+                    let error = UserMessageError::InvalidMessage
+                        .with_message("Clients should only send ice candidates after joining");
+                    let _ = server.send(&mut sender, &protocol, error).await;
+                }
+            }
+        }
+    }
+
+    server.close_ice_channel(&lobby, id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::routes;
+    use crate::protocol::PROTOCOL_HEADER;
+    use crate::server::{Server, ServerOptions};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use warp::http::{header, Response, StatusCode};
+    use warp::hyper::body::Bytes;
+
+    fn server_for(origin: &str) -> Arc<Server> {
+        Arc::new(Server::new(ServerOptions {
+            allowed_origins: Some(vec![origin.to_string()]),
+            ..ServerOptions::default()
+        }))
+    }
+
+    /// Opens a WebSocket at `path` from a page of `origin`
+    async fn open_ws(server: &Arc<Server>, path: &str, origin: &str) -> Response<Bytes> {
+        warp::test::request()
+            .path(path)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(header::ORIGIN, origin)
+            .reply(&routes(server.clone()))
+            .await
+    }
+
+    #[tokio::test]
+    async fn websockets_need_an_allowed_origin() {
+        let server = server_for("https://example.com");
+        for path in ["/api/host", "/api/client"] {
+            let response = open_ws(&server, path, "https://evil.com").await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+            let response = open_ws(&server, path, "https://example.com").await;
+            assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        }
+
+        // Programs other than browsers send no origin
+        let host = warp::test::ws()
+            .path("/api/host")
+            .handshake(routes(server.clone()))
+            .await;
+        assert!(host.is_ok());
+    }
+
+    #[tokio::test]
+    async fn invalid_protocol_headers_are_unsupported_versions() {
+        let server = Arc::new(Server::default());
+        let list = json!({
+            "type": "lobbies-list-request",
+            "maximumLobbies": 10,
+            "minimumCapacity": 0,
+        });
+        for version in ["two", "-1", "99"] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/api/client")
+                .header(PROTOCOL_HEADER, version)
+                .json(&list)
+                .reply(&routes(server.clone()))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["errorType"], "unsupportedVersion");
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/client")
+            .header(PROTOCOL_HEADER, "2")
+            .json(&list)
+            .reply(&routes(server.clone()))
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["type"], "lobbies-list");
+    }
+
+    #[tokio::test]
+    async fn client_requests_follow_the_cors_options() {
+        let server = server_for("https://example.com");
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/api/client")
+            .header(header::ORIGIN, "https://example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .reply(&routes(server.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+
+        let list = json!({
+            "type": "lobbies-list-request",
+            "maximumLobbies": 10,
+            "minimumCapacity": 0,
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/client")
+            .header(header::ORIGIN, "https://example.com")
+            .json(&list)
+            .reply(&routes(server.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/client")
+            .header(header::ORIGIN, "https://evil.com")
+            .json(&list)
+            .reply(&routes(server.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,688 @@
+use clap::Parser;
+use rtc_lobby::log;
+use rtc_lobby::{CharacterClass, Normalization, ServerOptions};
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+use warp::http::header::HeaderName;
+use warp::http::uri::{Authority, Scheme};
+use warp::http::Method;
+
+const DEFAULT_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const DEFAULT_PORT: u16 = 3030;
+
+/// Settings are taken from the command line, then from the environment,
+/// then from the config file, and finally from the defaults.
+#[derive(Parser, Debug)]
+#[command(version, about = "Signalling server for WebRTC lobbies")]
+struct Args {
+    /// Path to a TOML config file
+    #[arg(short, long, env = "RTC_LOBBY_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to bind the server to [default: 127.0.0.1]
+    #[arg(long, env = "RTC_LOBBY_ADDRESS")]
+    address: Option<IpAddr>,
+
+    /// Port to listen on [default: 3030]
+    #[arg(short, long, env = "RTC_LOBBY_PORT")]
+    port: Option<u16>,
+
+    /// Maximum size in bytes of a request body on /api/client [default: 2048]
+    #[arg(long, env = "RTC_LOBBY_CLIENT_BODY_LIMIT")]
+    client_body_limit: Option<u64>,
+
+    /// Maximum size in bytes of a message on /api/host [default: 16384]
+    #[arg(long, env = "RTC_LOBBY_HOST_MESSAGE_LIMIT")]
+    host_message_limit: Option<u64>,
+
+    /// Maximum number of lobbies open at the same time [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_MAX_LOBBIES")]
+    max_lobbies: Option<usize>,
+
+    /// Upper bound for the max clients of a lobby [default: 65535]
+    #[arg(long, env = "RTC_LOBBY_MAX_CLIENTS")]
+    max_clients: Option<u16>,
+
+    /// Maximum size in bytes of the keys and values of the metadata of a lobby.
+    /// 0 forbids metadata [default: 1024]
+    #[arg(long, env = "RTC_LOBBY_MAX_METADATA_SIZE")]
+    max_metadata_size: Option<usize>,
+
+    /// Maximum number of connections standing by to host a lobby.
+    /// 0 disables host migration [default: 8]
+    #[arg(long, env = "RTC_LOBBY_MAX_CANDIDATES")]
+    max_candidates: Option<usize>,
+
+    /// Minimum number of characters of a lobby name [default: 1]
+    #[arg(long, env = "RTC_LOBBY_MIN_LOBBY_NAME_LENGTH")]
+    min_lobby_name_length: Option<usize>,
+
+    /// Maximum number of characters of a lobby name [default: 64]
+    #[arg(long, env = "RTC_LOBBY_MAX_LOBBY_NAME_LENGTH")]
+    max_lobby_name_length: Option<usize>,
+
+    /// Characters allowed in lobby names, separated by commas: letters, digits,
+    /// spaces, punctuation or symbols [default: all of them]
+    #[arg(long, env = "RTC_LOBBY_LOBBY_NAME_CHARACTERS", value_delimiter = ',')]
+    lobby_name_characters: Option<Vec<CharacterClass>>,
+
+    /// Unicode normalization of the lobby names: none, nfc or nfkc [default: nfc]
+    #[arg(long, env = "RTC_LOBBY_LOBBY_NAME_NORMALIZATION")]
+    lobby_name_normalization: Option<Normalization>,
+
+    /// Lobby names no host can take, whatever their case, separated by commas
+    #[arg(long, env = "RTC_LOBBY_RESERVED_LOBBY_NAMES", value_delimiter = ',')]
+    reserved_lobby_names: Option<Vec<String>>,
+
+    /// Seconds a host has to send the create-lobby message [default: 10]
+    #[arg(long, env = "RTC_LOBBY_CREATE_LOBBY_TIMEOUT")]
+    create_lobby_timeout: Option<u64>,
+
+    /// Seconds a host has to answer a join request [default: 10]
+    #[arg(long, env = "RTC_LOBBY_JOIN_TIMEOUT")]
+    join_timeout: Option<u64>,
+
+    /// Seconds a client WebSocket can stay silent, before its join-request or
+    /// between ice candidates [default: 30]
+    #[arg(long, env = "RTC_LOBBY_CLIENT_IDLE_TIMEOUT")]
+    client_idle_timeout: Option<u64>,
+
+    /// Seconds the pending joins have to be answered after a SIGTERM [default: 10]
+    #[arg(long, env = "RTC_LOBBY_SHUTDOWN_GRACE_PERIOD")]
+    shutdown_grace_period: Option<u64>,
+
+    /// Seconds a lobby is kept after its host disconnects, so it can resume it.
+    /// 0 closes it at once [default: 10]
+    #[arg(long, env = "RTC_LOBBY_HOST_RESUME_PERIOD")]
+    host_resume_period: Option<u64>,
+
+    /// Directory of the lobby database. Without it lobbies are only kept in memory
+    #[arg(long, env = "RTC_LOBBY_DATABASE")]
+    database: Option<PathBuf>,
+
+    /// Seconds the name of a closed lobby stays reserved for its host [default: 60]
+    #[arg(long, env = "RTC_LOBBY_RESERVATION_PERIOD")]
+    reservation_period: Option<u64>,
+
+    /// PEM file with the TLS certificate chain. With it the server speaks HTTPS and WSS
+    #[arg(long, env = "RTC_LOBBY_TLS_CERTIFICATE")]
+    tls_certificate: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, env = "RTC_LOBBY_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Origins allowed to use the API from a browser, separated by commas [default: any]
+    #[arg(long, env = "RTC_LOBBY_ALLOWED_ORIGINS", value_delimiter = ',')]
+    allowed_origins: Option<Vec<String>>,
+
+    /// Methods allowed in CORS requests to /api/client [default: POST]
+    #[arg(long, env = "RTC_LOBBY_ALLOWED_METHODS", value_delimiter = ',')]
+    allowed_methods: Option<Vec<String>>,
+
+    /// Headers allowed in CORS requests to /api/client [default: content-type, rtc-lobby-protocol]
+    #[arg(long, env = "RTC_LOBBY_ALLOWED_HEADERS", value_delimiter = ',')]
+    allowed_headers: Option<Vec<String>>,
+
+    /// Lobbies each IP address can create per minute [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_CREATE_LOBBY_RATE")]
+    create_lobby_rate: Option<u32>,
+
+    /// Join requests each IP address can send per minute [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_JOIN_REQUEST_RATE")]
+    join_request_rate: Option<u32>,
+
+    /// Lobby list requests each IP address can send per minute [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_LOBBIES_LIST_RATE")]
+    lobbies_list_rate: Option<u32>,
+
+    /// Standby requests each IP address can send per minute [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_STANDBY_HOST_RATE")]
+    standby_host_rate: Option<u32>,
+
+    /// Lobbies each IP address can have open at the same time [default: unlimited]
+    #[arg(long, env = "RTC_LOBBY_MAX_LOBBIES_PER_IP")]
+    max_lobbies_per_ip: Option<usize>,
+
+    /// Bearer token of the admin API on `/admin`. Without it the API is disabled.
+    /// Prefer the environment variable, flags are visible to other users
+    #[arg(long, env = "RTC_LOBBY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Events printed: off, error, warn, info or debug, or directives
+    /// like `warn,rtc_lobby=info` [default: warn]
+    #[arg(long, env = "RTC_LOBBY_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// Output of the events: text or json [default: text]
+    #[arg(long, env = "RTC_LOBBY_LOG_FORMAT")]
+    log_format: Option<log::Format>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: FileServerConfig,
+    lobby: FileLobbyConfig,
+    lobby_names: FileLobbyNamesConfig,
+    timeouts: FileTimeoutsConfig,
+    tls: FileTlsConfig,
+    cors: FileCorsConfig,
+    database: FileDatabaseConfig,
+    rate_limit: FileRateLimitConfig,
+    admin: FileAdminConfig,
+    log: FileLogConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileServerConfig {
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    client_body_limit: Option<u64>,
+    host_message_limit: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileLobbyConfig {
+    max_lobbies: Option<usize>,
+    max_clients: Option<u16>,
+    max_metadata_size: Option<usize>,
+    max_candidates: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileLobbyNamesConfig {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    characters: Option<Vec<CharacterClass>>,
+    normalization: Option<Normalization>,
+    reserved: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileTimeoutsConfig {
+    create_lobby: Option<u64>,
+    join: Option<u64>,
+    client_idle: Option<u64>,
+    shutdown: Option<u64>,
+    host_resume: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileTlsConfig {
+    certificate: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileCorsConfig {
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: Option<Vec<String>>,
+    allowed_headers: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileDatabaseConfig {
+    path: Option<PathBuf>,
+    reservation_period: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileRateLimitConfig {
+    create_lobby: Option<u32>,
+    join_request: Option<u32>,
+    lobbies_list: Option<u32>,
+    standby_host: Option<u32>,
+    max_lobbies_per_ip: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileAdminConfig {
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileLogConfig {
+    level: Option<String>,
+    format: Option<log::Format>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub client_body_limit: u64,
+    pub host_message_limit: u64,
+    pub max_lobbies: Option<usize>,
+    pub max_clients: u16,
+    pub max_metadata_size: usize,
+    pub max_candidates: usize,
+    pub min_lobby_name_length: usize,
+    pub max_lobby_name_length: usize,
+    pub lobby_name_characters: Vec<CharacterClass>,
+    pub lobby_name_normalization: Normalization,
+    pub reserved_lobby_names: Vec<String>,
+    pub create_lobby_timeout: Duration,
+    pub join_timeout: Duration,
+    pub client_idle_timeout: Duration,
+    pub shutdown_grace_period: Duration,
+    pub host_resume_period: Duration,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// `None` allows any origin
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub database: Option<PathBuf>,
+    pub reservation_period: Duration,
+    pub create_lobby_rate: Option<u32>,
+    pub join_request_rate: Option<u32>,
+    pub lobbies_list_rate: Option<u32>,
+    pub standby_host_rate: Option<u32>,
+    pub max_lobbies_per_ip: Option<usize>,
+    pub admin_token: Option<String>,
+    pub log_level: String,
+    pub log_format: log::Format,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid {
+        setting: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(
+                    f,
+                    "could not read config file '{}': {error}",
+                    path.display()
+                )
+            }
+            ConfigError::Parse(path, error) => {
+                write!(f, "invalid config file '{}': {error}", path.display())
+            }
+            ConfigError::Invalid { setting, reason } => {
+                write!(f, "invalid value for '{setting}': {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FileConfig {
+    fn read(path: &PathBuf) -> Result<FileConfig, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| ConfigError::Read(path.clone(), error))?;
+        toml::from_str(&content).map_err(|error| ConfigError::Parse(path.clone(), error))
+    }
+}
+
+/// Origins are a scheme and a host, with an optional port but without path
+fn is_valid_origin(origin: &str) -> bool {
+    let Some((scheme, authority)) = origin.split_once("://") else {
+        return false;
+    };
+
+    Scheme::try_from(scheme).is_ok()
+        && !authority.contains('@')
+        && Authority::try_from(authority).is_ok()
+}
+
+impl Config {
+    /// Reads the command line arguments, the environment and the config file.
+    /// Invalid command line arguments terminate the process with a usage message.
+    pub fn load() -> Result<Config, ConfigError> {
+        let args = Args::parse();
+
+        let file = match &args.config {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+
+        let config = Config::merge(args, file);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The settings missing from the arguments and the file are the defaults of the server
+    fn merge(args: Args, file: FileConfig) -> Config {
+        let defaults = ServerOptions::default();
+        Config {
+            address: args
+                .address
+                .or(file.server.address)
+                .unwrap_or(DEFAULT_ADDRESS),
+            port: args.port.or(file.server.port).unwrap_or(DEFAULT_PORT),
+            client_body_limit: args
+                .client_body_limit
+                .or(file.server.client_body_limit)
+                .unwrap_or(defaults.client_body_limit),
+            host_message_limit: args
+                .host_message_limit
+                .or(file.server.host_message_limit)
+                .unwrap_or(defaults.host_message_limit),
+            max_lobbies: args
+                .max_lobbies
+                .or(file.lobby.max_lobbies)
+                .or(defaults.max_lobbies),
+            max_clients: args
+                .max_clients
+                .or(file.lobby.max_clients)
+                .unwrap_or(defaults.max_clients),
+            max_metadata_size: args
+                .max_metadata_size
+                .or(file.lobby.max_metadata_size)
+                .unwrap_or(defaults.max_metadata_size),
+            max_candidates: args
+                .max_candidates
+                .or(file.lobby.max_candidates)
+                .unwrap_or(defaults.max_candidates),
+            min_lobby_name_length: args
+                .min_lobby_name_length
+                .or(file.lobby_names.min_length)
+                .unwrap_or(defaults.lobby_names.min_length),
+            max_lobby_name_length: args
+                .max_lobby_name_length
+                .or(file.lobby_names.max_length)
+                .unwrap_or(defaults.lobby_names.max_length),
+            lobby_name_characters: args
+                .lobby_name_characters
+                .or(file.lobby_names.characters)
+                .unwrap_or(defaults.lobby_names.characters),
+            lobby_name_normalization: args
+                .lobby_name_normalization
+                .or(file.lobby_names.normalization)
+                .unwrap_or(defaults.lobby_names.normalization),
+            reserved_lobby_names: args
+                .reserved_lobby_names
+                .or(file.lobby_names.reserved)
+                .unwrap_or(defaults.lobby_names.reserved),
+            create_lobby_timeout: args
+                .create_lobby_timeout
+                .or(file.timeouts.create_lobby)
+                .map_or(defaults.create_lobby_timeout, Duration::from_secs),
+            join_timeout: args
+                .join_timeout
+                .or(file.timeouts.join)
+                .map_or(defaults.join_timeout, Duration::from_secs),
+            client_idle_timeout: args
+                .client_idle_timeout
+                .or(file.timeouts.client_idle)
+                .map_or(defaults.client_idle_timeout, Duration::from_secs),
+            shutdown_grace_period: args
+                .shutdown_grace_period
+                .or(file.timeouts.shutdown)
+                .map_or(defaults.shutdown_grace_period, Duration::from_secs),
+            host_resume_period: args
+                .host_resume_period
+                .or(file.timeouts.host_resume)
+                .map_or(defaults.host_resume_period, Duration::from_secs),
+            tls_certificate: args.tls_certificate.or(file.tls.certificate),
+            tls_key: args.tls_key.or(file.tls.key),
+            allowed_origins: args
+                .allowed_origins
+                .or(file.cors.allowed_origins)
+                .or(defaults.allowed_origins)
+                .filter(|origins| !origins.iter().any(|origin| origin == "*")),
+            allowed_methods: args
+                .allowed_methods
+                .or(file.cors.allowed_methods)
+                .unwrap_or(defaults.allowed_methods),
+            allowed_headers: args
+                .allowed_headers
+                .or(file.cors.allowed_headers)
+                .unwrap_or(defaults.allowed_headers),
+            database: args.database.or(file.database.path),
+            reservation_period: args
+                .reservation_period
+                .or(file.database.reservation_period)
+                .map_or(defaults.reservation_period, Duration::from_secs),
+            create_lobby_rate: args
+                .create_lobby_rate
+                .or(file.rate_limit.create_lobby)
+                .or(defaults.rate_limits.create_lobby),
+            join_request_rate: args
+                .join_request_rate
+                .or(file.rate_limit.join_request)
+                .or(defaults.rate_limits.join_request),
+            lobbies_list_rate: args
+                .lobbies_list_rate
+                .or(file.rate_limit.lobbies_list)
+                .or(defaults.rate_limits.lobbies_list),
+            standby_host_rate: args
+                .standby_host_rate
+                .or(file.rate_limit.standby_host)
+                .or(defaults.rate_limits.standby_host),
+            max_lobbies_per_ip: args
+                .max_lobbies_per_ip
+                .or(file.rate_limit.max_lobbies_per_ip)
+                .or(defaults.rate_limits.max_lobbies_per_ip),
+            admin_token: args
+                .admin_token
+                .or(file.admin.token)
+                .or(defaults.admin_token),
+            log_level: args
+                .log_level
+                .or(file.log.level)
+                .unwrap_or_else(|| "warn".to_string()),
+            log_format: args
+                .log_format
+                .or(file.log.format)
+                .unwrap_or(log::Format::Text),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |setting, reason| Err(ConfigError::Invalid { setting, reason });
+
+        if self.client_body_limit == 0 {
+            return invalid("client_body_limit", "must be greater than 0");
+        }
+        if self.host_message_limit == 0 {
+            return invalid("host_message_limit", "must be greater than 0");
+        }
+        if self.max_lobbies == Some(0) {
+            return invalid("max_lobbies", "must be greater than 0");
+        }
+        if self.max_clients == 0 {
+            return invalid("max_clients", "must be greater than 0");
+        }
+        if self.max_lobby_name_length == 0 {
+            return invalid("max_lobby_name_length", "must be greater than 0");
+        }
+        if self.min_lobby_name_length > self.max_lobby_name_length {
+            return invalid(
+                "min_lobby_name_length",
+                "must not be greater than max_lobby_name_length",
+            );
+        }
+        if self.lobby_name_characters.is_empty() {
+            return invalid("lobby_name_characters", "must allow at least one class");
+        }
+        if self.create_lobby_timeout.is_zero() {
+            return invalid("create_lobby_timeout", "must be at least 1 second");
+        }
+        if self.join_timeout.is_zero() {
+            return invalid("join_timeout", "must be at least 1 second");
+        }
+        if self.client_idle_timeout.is_zero() {
+            return invalid("client_idle_timeout", "must be at least 1 second");
+        }
+        if self.tls_certificate.is_some() != self.tls_key.is_some() {
+            return invalid("tls", "the certificate and the key must be set together");
+        }
+        for origin in self.allowed_origins.iter().flatten() {
+            if !is_valid_origin(origin) {
+                return invalid(
+                    "allowed_origins",
+                    "expected origins like https://example.com",
+                );
+            }
+        }
+        for method in &self.allowed_methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                return invalid("allowed_methods", "expected HTTP methods like POST");
+            }
+        }
+        for header in &self.allowed_headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return invalid("allowed_headers", "expected HTTP header names");
+            }
+        }
+        if self.admin_token.as_deref().is_some_and(str::is_empty) {
+            return invalid("admin_token", "must not be empty");
+        }
+        if log::parse_filter(&self.log_level).is_err() {
+            return invalid(
+                "log_level",
+                "expected a level or directives like warn,rtc_lobby=info",
+            );
+        }
+        if self.create_lobby_rate == Some(0) {
+            return invalid("create_lobby_rate", "must be greater than 0");
+        }
+        if self.join_request_rate == Some(0) {
+            return invalid("join_request_rate", "must be greater than 0");
+        }
+        if self.lobbies_list_rate == Some(0) {
+            return invalid("lobbies_list_rate", "must be greater than 0");
+        }
+        if self.standby_host_rate == Some(0) {
+            return invalid("standby_host_rate", "must be greater than 0");
+        }
+        if self.max_lobbies_per_ip == Some(0) {
+            return invalid("max_lobbies_per_ip", "must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::sync::{Mutex, PoisonError};
+
+    /// Serializes the tests that change the environment of the process
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    /// Reads the arguments with only the `RTC_LOBBY_*` variables of `env` set.
+    /// The variables of the process are put back afterwards
+    fn parse_with_env(args: &[&str], env: &[(&str, &str)], file: &str) -> Config {
+        let _lock = ENVIRONMENT.lock().unwrap_or_else(PoisonError::into_inner);
+        let ambient: Vec<(OsString, OsString)> = std::env::vars_os()
+            .filter(|(key, _)| key.to_string_lossy().starts_with("RTC_LOBBY_"))
+            .collect();
+        for (key, _) in &ambient {
+            std::env::remove_var(key);
+        }
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+
+        let parsed = Args::try_parse_from(["rtc-lobby"].iter().chain(args));
+
+        for (key, _) in env {
+            std::env::remove_var(key);
+        }
+        for (key, value) in ambient {
+            std::env::set_var(key, value);
+        }
+        Config::merge(parsed.unwrap(), toml::from_str(file).unwrap())
+    }
+
+    fn parse(args: &[&str], file: &str) -> Config {
+        parse_with_env(args, &[], file)
+    }
+
+    #[test]
+    fn settings_follow_their_precedence() {
+        let file = "[timeouts]\njoin = 30\n\n[server]\nport = 4000";
+
+        let env = [("RTC_LOBBY_JOIN_TIMEOUT", "20")];
+        let config = parse_with_env(&["--join-timeout", "40"], &env, file);
+        assert_eq!(config.join_timeout, Duration::from_secs(40));
+        let config = parse_with_env(&[], &env, file);
+        assert_eq!(config.join_timeout, Duration::from_secs(20));
+        assert_eq!(config.port, 4000);
+
+        let config = parse(&[], file);
+        assert_eq!(config.join_timeout, Duration::from_secs(30));
+        assert_eq!(config.port, 4000);
+
+        let config = parse(&[], "");
+        let defaults = ServerOptions::default();
+        assert_eq!(config.join_timeout, defaults.join_timeout);
+        assert_eq!(config.client_body_limit, defaults.client_body_limit);
+        assert_eq!(
+            config.max_lobby_name_length,
+            defaults.lobby_names.max_length
+        );
+        assert_eq!(config.allowed_headers, defaults.allowed_headers);
+        assert_eq!(config.port, DEFAULT_PORT);
+    }
+
+    #[test]
+    fn any_origin_is_allowed_with_a_star() {
+        let config = parse(&["--allowed-origins", "https://example.com,*"], "");
+        assert_eq!(config.allowed_origins, None);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        let invalid_setting = |config: Config| match config.validate() {
+            Err(ConfigError::Invalid { setting, .. }) => setting,
+            result => panic!("expected an invalid setting, got {result:?}"),
+        };
+
+        assert!(parse(&[], "").validate().is_ok());
+        assert_eq!(
+            invalid_setting(parse(&["--client-body-limit", "0"], "")),
+            "client_body_limit"
+        );
+        assert_eq!(
+            invalid_setting(parse(&[], "[lobby_names]\nmin_length = 10\nmax_length = 5")),
+            "min_lobby_name_length"
+        );
+        assert_eq!(invalid_setting(parse(&["--tls-key", "key.pem"], "")), "tls");
+        assert_eq!(
+            invalid_setting(parse(&["--allowed-origins", "example.com"], "")),
+            "allowed_origins"
+        );
+        assert_eq!(
+            invalid_setting(parse(&["--allowed-headers", "bad header"], "")),
+            "allowed_headers"
+        );
+        assert_eq!(
+            invalid_setting(parse(&["--log-level", "rtc_lobby=loud"], "")),
+            "log_level"
+        );
+        assert_eq!(
+            invalid_setting(parse(&[], "[rate_limit]\njoin_request = 0")),
+            "join_request_rate"
+        );
+    }
+}
@@ -0,0 +1,3 @@
+/// Most failures are already reported to the user when they happen,
+/// so by default the caller only needs to know that something went wrong.
+pub type Result<T, E = ()> = std::result::Result<T, E>;
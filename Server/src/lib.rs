@@ -0,0 +1,36 @@
+//! Signalling server for WebRTC lobbies.
+//!
+//! The routes can be mounted inside any warp application:
+//!
+//! ```no_run
+//! use rtc_lobby::{Server, ServerOptions};
+//! use std::sync::Arc;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let server = Arc::new(Server::new(ServerOptions::default()));
+//! warp::serve(rtc_lobby::routes(server))
+//!     .run(([127, 0, 0, 1], 3030))
+//!     .await;
+//! # }
+//! ```
+
+mod admin;
+mod api;
+mod error;
+pub mod log;
+mod message;
+mod metrics;
+pub mod protocol;
+mod server;
+mod tls;
+
+pub use admin::api_admin;
+pub use api::{api_client, api_health, api_host, api_metrics, routes};
+pub use message::{LobbyDetails, UserMessage, UserMessageError};
+pub use server::{
+    CharacterClass, HostChannel, Lobby, LobbyDatabase, LobbyHandle, LobbyInfo, NameRules,
+    Normalization, RamDatabase, RateLimitedAction, RateLimits, SecretHash, Server, ServerOptions,
+    SledDatabase,
+};
+pub use tls::{serve_tls, TlsOptions};
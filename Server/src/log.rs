@@ -1,26 +1,71 @@
-#![allow(unused_macros, unused_imports)]
+//! Output of the server events.
+//!
+//! The events are emitted with the `tracing` macros, so applications embedding
+//! the server can collect them with their own subscriber instead of calling [`init`].
 
-/// For normal and correct user actions
-macro_rules! user_action {
-    ($($x:tt)*) => {
-        // println!("[USER] {}", format!($($x)*));
-    }
+use serde::Deserialize;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One human readable line per event
+    Text,
+    /// One JSON object per line, with the fields of the event and its spans
+    Json,
 }
 
-/// For Thing that should not happend if the user is well implemented
-macro_rules! user_error {
-    ($($x:tt)*) => { 
-        println!("[USER-ERROR] {}", format!($($x)*));
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err("expected one of: text, json".to_string()),
+        }
     }
 }
 
-/// Internal server errors
-macro_rules! error {
-    ($($x:tt)*) => { 
-        println!("[ERROR] {}", format!($($x)*));
-    }
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Checks a filter like `warn` or `warn,rtc_lobby=info`
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|error| error.to_string())
 }
 
-pub(crate) use user_action;
-pub(crate) use user_error;
-pub(crate) use error;
+/// Prints the events that pass the filter to the standard output.
+/// It can only be called once.
+pub fn init(filter: &str, format: Format) -> Result<(), String> {
+    let (filter, handle) = reload::Layer::new(parse_filter(filter)?);
+    FILTER
+        .set(handle)
+        .map_err(|_| "the log output is already initialized".to_string())?;
+
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = match format {
+        Format::Text => registry
+            .with(fmt::layer().with_ansi(std::io::stdout().is_terminal()))
+            .try_init(),
+        Format::Json => registry
+            .with(fmt::layer().json().with_current_span(true))
+            .try_init(),
+    };
+    result.map_err(|error| error.to_string())
+}
+
+/// Replaces the filter of the output while the server runs
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    let Some(handle) = FILTER.get() else {
+        return Err("the log output is not initialized".to_string());
+    };
+
+    handle
+        .reload(parse_filter(filter)?)
+        .map_err(|error| error.to_string())
+}
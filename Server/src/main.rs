@@ -1,75 +1,117 @@
-#![feature(map_try_insert, trait_alias, async_closure)]
+mod config;
 
-mod log;
-mod message;
-mod server;
-
-use futures_util::*;
-use lazy_static::lazy_static;
-use message::*;
-use server::*;
-use warp::ws::WebSocket;
-use warp::{reject, reply, Filter};
-
-lazy_static! {
-    static ref SERVER: Server = Server::default();
-}
+use config::Config;
+use rtc_lobby::{log, NameRules, RateLimits, Server, ServerOptions, SledDatabase, TlsOptions};
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() {
-    let api_client = warp::path!("api" / "client")
-        .and(warp::post())
-        .and(warp::body::content_length_limit(1024 * 2))
-        .and(warp::body::json())
-        .and_then(|message: UserMessage| handle_client(&SERVER, message));
-
-    let api_host = warp::path!("api" / "host")
-        .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(async move |host| handle_host(&SERVER, host).await));
-
-    let routes = api_client.or(api_host);
-
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
-
-async fn handle_host(server: &Server, host: WebSocket) {
-    log::user_action!("Host connected");
-
-    let (mut sender, mut receiver) = host.split();
+    let config = Config::load().unwrap_or_else(|error| {
+        eprintln!("error: {error}");
+        std::process::exit(2);
+    });
+
+    if let Err(error) = log::init(&config.log_level, config.log_format) {
+        eprintln!("error: could not initialize the log output: {error}");
+        std::process::exit(2);
+    }
 
-    let Some(create_message) = UserMessage::from(receiver.next().await) else {
-        log::user_error!("Host should have sended a create-lobby message");
-        let _ = sender.send(UserMessageError::InvalidMessage.into()).await;
-        return;
+    let options = ServerOptions {
+        client_body_limit: config.client_body_limit,
+        host_message_limit: config.host_message_limit,
+        max_lobbies: config.max_lobbies,
+        max_clients: config.max_clients,
+        create_lobby_timeout: config.create_lobby_timeout,
+        reservation_period: config.reservation_period,
+        join_timeout: config.join_timeout,
+        client_idle_timeout: config.client_idle_timeout,
+        rate_limits: RateLimits {
+            create_lobby: config.create_lobby_rate,
+            join_request: config.join_request_rate,
+            lobbies_list: config.lobbies_list_rate,
+            standby_host: config.standby_host_rate,
+            max_lobbies_per_ip: config.max_lobbies_per_ip,
+        },
+        allowed_origins: config.allowed_origins,
+        allowed_methods: config.allowed_methods,
+        allowed_headers: config.allowed_headers,
+        shutdown_grace_period: config.shutdown_grace_period,
+        admin_token: config.admin_token,
+        host_resume_period: config.host_resume_period,
+        max_metadata_size: config.max_metadata_size,
+        max_candidates: config.max_candidates,
+        lobby_names: NameRules {
+            min_length: config.min_lobby_name_length,
+            max_length: config.max_lobby_name_length,
+            characters: config.lobby_name_characters,
+            normalization: config.lobby_name_normalization,
+            reserved: config.reserved_lobby_names,
+        },
     };
 
-    let lobby_name = server.create_lobby_from_message(create_message, sender);
-    let mut lobby_name = match lobby_name.await {
-        Ok(lobby_name) => lobby_name,
-        Err(()) => {
-            log::user_error!("Host could not the create lobby");
-            return;
+    let server = match &config.database {
+        None => Server::new(options),
+        Some(path) => match SledDatabase::open(path, config.reservation_period) {
+            Ok(database) => Server::with_database(options, database),
+            Err(error) => {
+                eprintln!(
+                    "error: could not open database '{}': {error}",
+                    path.display()
+                );
+                std::process::exit(1);
+            }
+        },
+    };
+    let server = Arc::new(server);
+
+    let routes = rtc_lobby::routes(server.clone());
+    let address = (config.address, config.port);
+
+    let serve = async {
+        match (config.tls_certificate, config.tls_key) {
+            (Some(certificate), Some(key)) => {
+                let tls = TlsOptions { certificate, key };
+                if let Err(error) = rtc_lobby::serve_tls(routes, address.into(), tls).await {
+                    eprintln!("error: could not start the TLS server: {error}");
+                    std::process::exit(1);
+                }
+            }
+            _ => warp::serve(routes).run(address).await,
         }
     };
 
-    while let Some(message) = UserMessage::from(receiver.next().await) {
-        log::user_action!("Received message from host of '{lobby_name}'");
-        match server.handle_host_message(&lobby_name, message).await {
-            Ok(name) => lobby_name = name,
-            Err(()) => break,
-        }
-    }
+    // The routes keep being served until the pending joins are drained
+    let shutdown = async {
+        shutdown_signal().await;
+        server.shutdown().await;
+    };
 
-    server.close_lobby(&lobby_name).await;
+    tokio::select! {
+        () = serve => {}
+        () = shutdown => {}
+    }
 }
 
-async fn handle_client(
-    server: &Server,
-    message: UserMessage,
-) -> Result<reply::Json, reject::Rejection> {
-    log::user_action!("Client connected");
+/// Waits for a SIGTERM or a Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                eprintln!("error: could not listen for SIGTERM: {error}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let response = server.handle_user_message(message).await;
-    Ok(reply::json(&response))
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
 }
-
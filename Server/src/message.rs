@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
+use serde_path_to_error::Segment;
+use std::collections::BTreeMap;
 use warp::ws::Message;
 
+/// Key/value pairs set by the host, like the game mode or the map of the lobby
+pub type Metadata = BTreeMap<String, String>;
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct LobbyDetails {
@@ -8,78 +13,457 @@ pub struct LobbyDetails {
     pub public_lobby: bool,
     pub max_clients: u16,
     pub client_count: u16,
+    /// Set by the server. Clients have to send the password to join
+    #[serde(default)]
+    pub has_password: bool,
+    #[serde(default)]
+    pub metadata: Metadata,
 }
 
 impl LobbyDetails {
     pub fn capacity(&self) -> u16 {
         self.max_clients.saturating_sub(self.client_count)
     }
+
+    /// Bytes taken by the keys and values of the metadata
+    pub fn metadata_size(&self) -> usize {
+        metadata_size(&self.metadata)
+    }
+}
+
+pub fn metadata_size(metadata: &Metadata) -> usize {
+    metadata
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
+}
+
+/// Condition on a metadata value of the listed lobbies.
+/// Lobbies without the key never match
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LobbyFilter {
+    pub key: String,
+    pub equals: Option<String>,
+    /// With `min` or `max`, the value has to be a number in the range, bounds included
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Order of the listed lobbies. Ties are sorted by name
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LobbySort {
+    Name,
+    /// Free places, counting the clients that are still joining
+    Capacity,
+    ClientCount,
+    #[default]
+    CreationTime,
+}
+
+impl LobbySort {
+    /// Value of the `sort` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            LobbySort::Name => "name",
+            LobbySort::Capacity => "capacity",
+            LobbySort::ClientCount => "clientCount",
+            LobbySort::CreationTime => "creationTime",
+        }
+    }
+}
+
+impl LobbyFilter {
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        let Some(value) = metadata.get(&self.key) else {
+            return false;
+        };
+
+        if self.equals.as_ref().is_some_and(|equals| equals != value) {
+            return false;
+        }
+
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+        let Ok(number) = value.trim().parse::<f64>() else {
+            return false;
+        };
+        self.min.is_none_or(|min| number >= min) && self.max.is_none_or(|max| number <= max)
+    }
+}
+
+/// Declares the messages with the value of their `type`. A received message is
+/// read as a struct with the fields of its type, so that `serde_path_to_error` can
+/// give the path of an invalid field, which serde loses in internally tagged enums
+macro_rules! user_messages {
+    (
+        $(#[$enum_attr:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$attr:meta])*
+                $variant:ident = $type_name:literal {
+                    $($(#[$field_attr:meta])* $field:ident: $ty:ty),* $(,)?
+                },
+            )*
+        }
+    ) => {
+        $(#[$enum_attr])*
+        pub enum $name {
+            $(
+                $(#[$attr])*
+                #[serde(rename = $type_name)]
+                $variant { $($(#[$field_attr])* $field: $ty),* },
+            )*
+        }
+
+        impl $name {
+            /// Value of the `type` field
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    $($name::$variant { .. } => $type_name,)*
+                }
+            }
+
+            /// Reads the fields of a message of this type. `None` if the type is unknown
+            fn parse_fields(
+                message_type: &str,
+                fields: serde_json::Value,
+            ) -> Option<Result<$name, serde_path_to_error::Error<serde_json::Error>>> {
+                match message_type {
+                    $($type_name => {
+                        #[derive(Deserialize)]
+                        $(#[$attr])*
+                        struct Fields {
+                            $($(#[$field_attr])* $field: $ty),*
+                        }
+
+                        let fields = serde_path_to_error::deserialize(fields);
+                        Some(fields.map(|Fields { $($field),* }| $name::$variant { $($field),* }))
+                    })*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+user_messages! {
+    /// The messages a host sends can have a `requestId`. The server echoes it in the
+    /// message that answers it, or in the error it causes
+    #[derive(Serialize, Debug)]
+    #[serde(tag = "type")]
+    pub enum UserMessage {
+        /// First message of a connection, with the version of the protocol it speaks and
+        /// the features it knows. The server answers with the ones they agreed on
+        #[serde(rename_all = "camelCase")]
+        Hello = "hello" {
+            version: u32,
+            #[serde(default)]
+            features: Vec<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        JoinRequest = "join-request" {
+            lobby_name: Option<String>,
+            offer: String,
+            id: Option<u32>,
+            password: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        JoinInvitation = "join-invitation" {
+            answer: String,
+            id: Option<u32>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// The host refuses a join request
+        #[serde(rename_all = "camelCase")]
+        JoinRejection = "join-rejection" {
+            id: Option<u32>,
+            reason: String,
+            code: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// Relayed between the host and a client joining through a WebSocket
+        #[serde(rename_all = "camelCase")]
+        IceCandidate = "ice-candidate" {
+            candidate: String,
+            id: Option<u32>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// Tells the host that a join request is no longer waiting for an answer.
+        /// Has the `requestId` of the answer that arrived too late
+        #[serde(rename_all = "camelCase")]
+        JoinExpired = "join-expired" {
+            id: u32,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        LobbyDetails = "lobby-details" {
+            details: LobbyDetails,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        CreateLobby = "create-lobby" {
+            lobby_name: Option<String>,
+            public_lobby: bool,
+            max_clients: u16,
+            /// Lets the host reclaim the lobby name for a while after disconnecting
+            reservation_key: Option<String>,
+            /// Clients have to send it with their join requests
+            password: Option<String>,
+            #[serde(default)]
+            metadata: Metadata,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        LobbiesListRequest = "lobbies-list-request" {
+            maximum_lobbies: usize,
+            minimum_capacity: u16,
+            /// Only lobbies matching every filter are listed
+            #[serde(default)]
+            filters: Vec<LobbyFilter>,
+            #[serde(default)]
+            sort: LobbySort,
+            #[serde(default)]
+            descending: bool,
+            /// `nextCursor` of the previous page
+            cursor: Option<String>,
+        },
+        #[serde(rename_all = "camelCase")]
+        LobbiesList = "lobbies-list" {
+            lobbies: Vec<LobbyDetails>,
+            /// Lobbies matching the request in every page
+            total: usize,
+            /// Set if there are more lobbies to list
+            next_cursor: Option<String>,
+        },
+        /// Sent to the host after creating its lobby.
+        /// It can take the lobby back with it if its connection drops
+        #[serde(rename_all = "camelCase")]
+        ResumeToken = "resume-token" { resume_token: String },
+        /// Sent by a host instead of create-lobby to take back its lobby
+        #[serde(rename_all = "camelCase")]
+        ResumeLobby = "resume-lobby" {
+            lobby_name: String,
+            resume_token: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// Sent by a client instead of create-lobby to stand by as the next host of a lobby
+        #[serde(rename_all = "camelCase")]
+        StandbyHost = "standby-host" {
+            lobby_name: String,
+            password: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// Sent to a standby host. The host can designate it as its successor with the id
+        #[serde(rename_all = "camelCase")]
+        StandbyAccepted = "standby-accepted" {
+            candidate_id: u32,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// The host picks the standby host that takes the lobby when it leaves.
+        /// Without id, the server picks the one that stood by first
+        #[serde(rename_all = "camelCase")]
+        DesignateSuccessor = "designate-successor" {
+            candidate_id: Option<u32>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+        /// Answers a message of the host that has no other reply, once it has been handled.
+        /// Only sent to the messages with a `requestId`
+        #[serde(rename_all = "camelCase")]
+        Ack = "ack" { request_id: String },
+        /// Tells the hosts that the server is stopping.
+        /// Pending joins are still answered during the grace period, in seconds
+        #[serde(rename_all = "camelCase")]
+        ServerShutdown = "server-shutdown" { grace_period: u64 },
+        #[serde(rename_all = "camelCase")]
+        Error = "error" {
+            error_type: UserMessageError,
+            /// What went wrong, for the developers
+            #[serde(default, skip_serializing_if = "String::is_empty")]
+            message: String,
+            /// Field of the message that is wrong
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            field: Option<String>,
+            /// `requestId` of the message the error answers
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            request_id: Option<String>,
+        },
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(tag = "type", rename_all = "kebab-case")]
-pub enum UserMessage {
-    #[serde(rename_all = "camelCase")]
-    JoinRequest {
-        lobby_name: Option<String>,
-        offer: String,
-        id: Option<u32>,
-    },
-    JoinInvitation {
-        answer: String,
-        id: Option<u32>,
-    },
-    LobbyDetails {
-        details: LobbyDetails,
-    },
-    #[serde(rename_all = "camelCase")]
-    CreateLobby {
-        lobby_name: Option<String>,
-        public_lobby: bool,
-        max_clients: u16,
-    },
-    #[serde(rename_all = "camelCase")]
-    LobbiesListRequest {
-        maximum_lobbies: usize,
-        minimum_capacity: u16,
-    },
-    LobbiesList {
-        lobbies: Vec<LobbyDetails>,
-    },
-    #[serde(rename_all = "camelCase")]
-    Error {
-        error_type: UserMessageError,
-    },
-}
-
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum UserMessageError {
     LobbyNotFound,
     LobbyAlreadyExists,
+    /// The message is valid, but not expected from this connection or at this time
     InvalidMessage,
+    TooManyLobbies,
+    LobbyFull,
+    JoinTimeout,
+    WrongPassword,
+    RateLimited,
+    ServerShuttingDown,
+    MetadataTooLarge,
+    UnsupportedVersion,
+    /// The message is not JSON text
+    MalformedJson,
+    UnknownType,
+    /// A field is missing, or has a wrong type or value
+    InvalidField,
+    /// The server failed, not the message
+    Internal,
 }
 
-impl Into<UserMessage> for UserMessageError {
-    fn into(self) -> UserMessage {
-        UserMessage::Error { error_type: self }
+impl UserMessageError {
+    /// Value of the `errorType` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            UserMessageError::LobbyNotFound => "lobbyNotFound",
+            UserMessageError::LobbyAlreadyExists => "lobbyAlreadyExists",
+            UserMessageError::InvalidMessage => "invalidMessage",
+            UserMessageError::TooManyLobbies => "tooManyLobbies",
+            UserMessageError::LobbyFull => "lobbyFull",
+            UserMessageError::JoinTimeout => "joinTimeout",
+            UserMessageError::WrongPassword => "wrongPassword",
+            UserMessageError::RateLimited => "rateLimited",
+            UserMessageError::ServerShuttingDown => "serverShuttingDown",
+            UserMessageError::MetadataTooLarge => "metadataTooLarge",
+            UserMessageError::UnsupportedVersion => "unsupportedVersion",
+            UserMessageError::MalformedJson => "malformedJson",
+            UserMessageError::UnknownType => "unknownType",
+            UserMessageError::InvalidField => "invalidField",
+            UserMessageError::Internal => "internal",
+        }
+    }
+
+    /// Message of the error when there is nothing more precise to say
+    pub fn description(&self) -> &'static str {
+        match self {
+            UserMessageError::LobbyNotFound => "There is no lobby with this name",
+            UserMessageError::LobbyAlreadyExists => "The lobby name is already taken",
+            UserMessageError::InvalidMessage => "The message is not expected here",
+            UserMessageError::TooManyLobbies => "The server can not open more lobbies",
+            UserMessageError::LobbyFull => "The lobby is full",
+            UserMessageError::JoinTimeout => "The host did not answer the join request in time",
+            UserMessageError::WrongPassword => "The password of the lobby is wrong",
+            UserMessageError::RateLimited => "Too many requests, try again later",
+            UserMessageError::ServerShuttingDown => "The server is shutting down",
+            UserMessageError::MetadataTooLarge => "The metadata of the lobby is too large",
+            UserMessageError::UnsupportedVersion => {
+                "The server does not speak this version of the protocol"
+            }
+            UserMessageError::MalformedJson => "The message is not valid JSON",
+            UserMessageError::UnknownType => "The type of the message is unknown",
+            UserMessageError::InvalidField => "A field of the message is invalid",
+            UserMessageError::Internal => "The server failed to handle the message",
+        }
+    }
+
+    /// Errors that version 1 of the protocol did not have are sent to its
+    /// connections as invalid messages
+    pub fn legacy(self) -> UserMessageError {
+        match self {
+            UserMessageError::MalformedJson
+            | UserMessageError::UnknownType
+            | UserMessageError::InvalidField
+            | UserMessageError::Internal => UserMessageError::InvalidMessage,
+            error => error,
+        }
+    }
+
+    /// Error with a description of what went wrong
+    pub fn with_message(self, message: impl Into<String>) -> UserMessage {
+        UserMessage::Error {
+            error_type: self,
+            message: message.into(),
+            field: None,
+            request_id: None,
+        }
+    }
+
+    /// Error answering the message with this `requestId`
+    pub fn with_request_id(self, request_id: Option<String>) -> UserMessage {
+        UserMessage::from(self).with_request_id(request_id)
+    }
+
+    /// Error about a field of the message it answers
+    pub fn with_field(self, field: &str, message: impl Into<String>) -> UserMessage {
+        UserMessage::Error {
+            error_type: self,
+            message: message.into(),
+            field: Some(field.to_string()),
+            request_id: None,
+        }
     }
 }
 
-impl Into<Message> for UserMessageError {
-    fn into(self) -> Message {
-        let msg: UserMessage = self.into();
-        let txt = serde_json::to_string(&msg).unwrap_or_else(|_| {
-            r#"{"type":"error","message":"Could not serialize the error"}"#.to_string()
-        });
-        Message::text(txt)
+/// Error with its description
+impl From<UserMessageError> for UserMessage {
+    fn from(error: UserMessageError) -> UserMessage {
+        error.with_message(error.description())
     }
 }
 
-impl Into<Message> for &UserMessage {
-    fn into(self) -> Message {
-        let txt = serde_json::to_string(self).unwrap_or_else(|_| {
-            r#"{"type":"error","message":"Could not serialize the message"}"#.to_string()
+/// Why a received message could not be read
+#[derive(Debug)]
+pub struct ParseError {
+    error_type: UserMessageError,
+    message: String,
+    field: Option<String>,
+    request_id: Option<String>,
+}
+
+impl ParseError {
+    fn new(error_type: UserMessageError, message: impl Into<String>) -> ParseError {
+        ParseError {
+            error_type,
+            message: message.into(),
+            field: None,
+            request_id: None,
+        }
+    }
+
+    fn with_field(mut self, field: impl Into<String>) -> ParseError {
+        self.field = Some(field.into());
+        self
+    }
+
+    fn with_request_id(mut self, request_id: Option<String>) -> ParseError {
+        self.request_id = request_id;
+        self
+    }
+}
+
+impl From<ParseError> for UserMessage {
+    fn from(error: ParseError) -> UserMessage {
+        let message = match error.field {
+            Some(field) => error.error_type.with_field(&field, error.message),
+            None => error.error_type.with_message(error.message),
+        };
+        message.with_request_id(error.request_id)
+    }
+}
+
+impl From<&UserMessage> for Message {
+    fn from(message: &UserMessage) -> Message {
+        let txt = serde_json::to_string(message).unwrap_or_else(|_| {
+            r#"{"type":"error","errorType":"internal","message":"Could not serialize the message"}"#
+                .to_string()
         });
         Message::text(txt)
     }
@@ -87,7 +471,43 @@ impl Into<Message> for &UserMessage {
 
 type WsMessage = Option<Result<Message, warp::Error>>;
 impl UserMessage {
-    pub fn from(message: WsMessage) -> Option<Self> {
+    /// `requestId` of the message
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            UserMessage::Hello { request_id, .. }
+            | UserMessage::JoinInvitation { request_id, .. }
+            | UserMessage::JoinRejection { request_id, .. }
+            | UserMessage::IceCandidate { request_id, .. }
+            | UserMessage::JoinExpired { request_id, .. }
+            | UserMessage::LobbyDetails { request_id, .. }
+            | UserMessage::CreateLobby { request_id, .. }
+            | UserMessage::ResumeLobby { request_id, .. }
+            | UserMessage::StandbyHost { request_id, .. }
+            | UserMessage::StandbyAccepted { request_id, .. }
+            | UserMessage::DesignateSuccessor { request_id, .. }
+            | UserMessage::Error { request_id, .. } => request_id.as_deref(),
+            UserMessage::Ack { request_id } => Some(request_id),
+            _ => None,
+        }
+    }
+
+    /// Echoes the `requestId` of the message this one answers.
+    /// Only replies to the host and errors have one
+    pub fn with_request_id(mut self, id: Option<String>) -> UserMessage {
+        match &mut self {
+            UserMessage::Hello { request_id, .. }
+            | UserMessage::JoinExpired { request_id, .. }
+            | UserMessage::LobbyDetails { request_id, .. }
+            | UserMessage::StandbyAccepted { request_id, .. }
+            | UserMessage::Error { request_id, .. } => *request_id = id,
+            _ => {}
+        }
+        self
+    }
+
+    /// Reads the next message of a WebSocket.
+    /// `None` once the WebSocket is closed or sends something else than text
+    pub fn receive(message: WsMessage) -> Option<Result<Self, ParseError>> {
         let Some(Ok(message)) = message else {
             return None;
         };
@@ -96,10 +516,264 @@ impl UserMessage {
             return None;
         };
 
-        let Ok(message)  = serde_json::from_str::<UserMessage>(message) else {
-            return None;
+        Some(Self::parse(message.as_bytes()))
+    }
+
+    pub fn parse(message: &[u8]) -> Result<Self, ParseError> {
+        let value: serde_json::Value = serde_json::from_slice(message)
+            .map_err(|error| ParseError::new(UserMessageError::MalformedJson, error.to_string()))?;
+
+        // The error still answers the request if the rest of the message is invalid
+        let request_id = value
+            .get("requestId")
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string);
+        Self::parse_value(value).map_err(|error| error.with_request_id(request_id))
+    }
+
+    fn parse_value(mut value: serde_json::Value) -> Result<Self, ParseError> {
+        let Some(message_type) = value.get("type") else {
+            let error = ParseError::new(UserMessageError::InvalidField, "missing field `type`");
+            return Err(error.with_field("type"));
+        };
+        let Some(message_type) = message_type.as_str().map(str::to_string) else {
+            let error = ParseError::new(UserMessageError::InvalidField, "`type` is not a string");
+            return Err(error.with_field("type"));
+        };
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("type");
+        }
+
+        let Some(message) = Self::parse_fields(&message_type, value) else {
+            let message = format!("unknown message type `{message_type}`");
+            return Err(ParseError::new(UserMessageError::UnknownType, message).with_field("type"));
+        };
+        message.map_err(|error| {
+            let mut field = format_path(error.path());
+            let error = error.into_inner();
+            // The path of a missing field is the object that lacks it
+            if let Some(missing) = missing_field(&error) {
+                field = match field.is_empty() {
+                    true => missing,
+                    false => format!("{field}.{missing}"),
+                };
+            }
+
+            let error = ParseError::new(UserMessageError::InvalidField, error.to_string());
+            match field.is_empty() {
+                true => error,
+                false => error.with_field(field),
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for UserMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        UserMessage::parse_value(value).map_err(|error| serde::de::Error::custom(error.message))
+    }
+}
+
+/// `details.lobbyName` or `filters[0].min`
+fn format_path(path: &serde_path_to_error::Path) -> String {
+    let mut formatted = String::new();
+    for segment in path.iter() {
+        match segment {
+            Segment::Map { key } if formatted.is_empty() => formatted += key,
+            Segment::Map { key } => formatted += &format!(".{key}"),
+            Segment::Seq { index } => formatted += &format!("[{index}]"),
+            Segment::Enum { .. } | Segment::Unknown => break,
+        }
+    }
+    formatted
+}
+
+/// Serde only names the missing field in the message of the error, written by the
+/// default `missing_field` of its `Error` trait
+fn missing_field(error: &serde_json::Error) -> Option<String> {
+    let message = error.to_string();
+    let field = message.strip_prefix("missing field `")?.split('`').next()?;
+    Some(field.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(key: &str, equals: Option<&str>, min: Option<f64>, max: Option<f64>) -> LobbyFilter {
+        LobbyFilter {
+            key: key.to_string(),
+            equals: equals.map(str::to_string),
+            min,
+            max,
+        }
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> Metadata {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn filter_needs_the_key() {
+        let metadata = metadata(&[("mode", "ctf")]);
+        assert!(filter("mode", None, None, None).matches(&metadata));
+        assert!(!filter("map", None, None, None).matches(&metadata));
+        assert!(!filter("map", None, Some(0.0), None).matches(&metadata));
+    }
+
+    #[test]
+    fn filter_compares_the_exact_value() {
+        let metadata = metadata(&[("mode", "ctf")]);
+        assert!(filter("mode", Some("ctf"), None, None).matches(&metadata));
+        assert!(!filter("mode", Some("CTF"), None, None).matches(&metadata));
+        assert!(!filter("mode", Some("ct"), None, None).matches(&metadata));
+    }
+
+    #[test]
+    fn filter_ranges_include_their_bounds() {
+        let metadata = metadata(&[("level", " 10 ")]);
+        assert!(filter("level", None, Some(10.0), Some(10.0)).matches(&metadata));
+        assert!(filter("level", None, Some(5.0), None).matches(&metadata));
+        assert!(filter("level", None, None, Some(12.5)).matches(&metadata));
+        assert!(!filter("level", None, Some(10.5), None).matches(&metadata));
+        assert!(!filter("level", None, None, Some(9.0)).matches(&metadata));
+    }
+
+    #[test]
+    fn filter_ranges_need_numbers() {
+        let metadata = metadata(&[("level", "ten")]);
+        assert!(!filter("level", None, Some(0.0), None).matches(&metadata));
+        assert!(filter("level", Some("ten"), None, None).matches(&metadata));
+        assert!(!filter("level", Some("ten"), None, Some(100.0)).matches(&metadata));
+    }
+
+    fn parse_error(message: &str) -> (UserMessageError, Option<String>) {
+        let error = UserMessage::parse(message.as_bytes()).unwrap_err();
+        (error.error_type, error.field)
+    }
+
+    fn invalid_field(field: &str) -> (UserMessageError, Option<String>) {
+        (UserMessageError::InvalidField, Some(field.to_string()))
+    }
+
+    #[test]
+    fn parse_reads_valid_messages() {
+        let message = UserMessage::parse(
+            br#"{"type":"create-lobby","publicLobby":true,"maxClients":4,"requestId":"7"}"#,
+        )
+        .unwrap();
+        assert!(matches!(message, UserMessage::CreateLobby { .. }));
+        assert_eq!(message.request_id(), Some("7"));
+    }
+
+    #[test]
+    fn parse_tells_malformed_json_from_unknown_types() {
+        assert_eq!(parse_error("{").0, UserMessageError::MalformedJson);
+        assert_eq!(
+            parse_error(r#"{"type":"teleport"}"#),
+            (UserMessageError::UnknownType, Some("type".to_string()))
+        );
+        assert_eq!(parse_error(r#"{"lobbyName":"a"}"#), invalid_field("type"));
+        assert_eq!(parse_error(r#"{"type":3}"#), invalid_field("type"));
+    }
+
+    #[test]
+    fn parse_names_fields_of_the_wrong_type() {
+        assert_eq!(
+            parse_error(r#"{"type":"create-lobby","publicLobby":true,"maxClients":"ten"}"#),
+            invalid_field("maxClients")
+        );
+        assert_eq!(
+            parse_error(r#"{"type":"join-request","offer":"o","id":-1}"#),
+            invalid_field("id")
+        );
+    }
+
+    #[test]
+    fn parse_names_missing_fields() {
+        assert_eq!(
+            parse_error(r#"{"type":"create-lobby","maxClients":4}"#),
+            invalid_field("publicLobby")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"lobby-details","details":{"lobbyName":"a","publicLobby":true,"maxClients":3}}"#
+            ),
+            invalid_field("details.clientCount")
+        );
+    }
+
+    #[test]
+    fn parse_gives_the_path_of_nested_fields() {
+        assert_eq!(
+            parse_error(
+                r#"{"type":"lobby-details","details":{"lobbyName":"a","publicLobby":"yes","maxClients":3,"clientCount":0}}"#
+            ),
+            invalid_field("details.publicLobby")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"lobbies-list-request","maximumLobbies":1,"minimumCapacity":0,"filters":[{"key":"a"},{"key":"b","min":"x"}]}"#
+            ),
+            invalid_field("filters[1].min")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"lobbies-list-request","maximumLobbies":1,"minimumCapacity":0,"filters":[{"key":"a","max":1,"near":2}]}"#
+            ),
+            invalid_field("filters[0].near")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"create-lobby","publicLobby":true,"maxClients":2,"metadata":{"mode":{"name":"ctf"}}}"#
+            ),
+            invalid_field("metadata.mode")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"create-lobby","publicLobby":true,"metadata":{"a":{"x":1},"b":{"x":1}}}"#
+            ),
+            invalid_field("metadata.a")
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"type":"lobbies-list-request","maximumLobbies":1,"minimumCapacity":0,"filters":[{"min":1}]}"#
+            ),
+            invalid_field("filters[0].key")
+        );
+    }
+
+    #[test]
+    fn parse_finds_the_invalid_field_of_large_messages() {
+        let mut filters = vec![r#"{"key":"a"}"#; 1000];
+        filters[999] = r#"{"key":"a","min":"x"}"#;
+        let filters = filters.join(",");
+        let message = format!(
+            r#"{{"type":"lobbies-list-request","maximumLobbies":1,"minimumCapacity":0,"filters":[{filters}]}}"#
+        );
+        assert_eq!(parse_error(&message), invalid_field("filters[999].min"));
+    }
+
+    #[test]
+    fn messages_are_read_back_from_their_json() {
+        let message = UserMessage::JoinExpired {
+            id: 3,
+            request_id: Some("4".to_string()),
         };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(json, r#"{"type":"join-expired","id":3,"requestId":"4"}"#);
+        let message: UserMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(message.type_name(), "join-expired");
+        assert_eq!(message.request_id(), Some("4"));
+    }
 
-        Some(message)
+    #[test]
+    fn parse_errors_keep_the_request_id() {
+        let error = UserMessage::parse(br#"{"type":"create-lobby","requestId":"9"}"#).unwrap_err();
+        assert_eq!(error.request_id.as_deref(), Some("9"));
     }
 }
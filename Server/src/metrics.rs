@@ -0,0 +1,133 @@
+//! Prometheus metrics of a server.
+//!
+//! Each server keeps its metrics in its own registry, so servers of the same process
+//! do not count for each other. They are exported with the metrics of the default
+//! registry of the `prometheus` crate, where an embedding application can add its own.
+
+use crate::message::UserMessage;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Whether a message comes from the host of a lobby or from a client
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Host,
+    Client,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    lobbies: IntGauge,
+    public_lobbies: IntGauge,
+    messages: IntCounterVec,
+    errors: IntCounterVec,
+    join_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        Metrics {
+            lobbies: register(
+                &registry,
+                IntGauge::new("rtc_lobby_lobbies", "Open lobbies").expect("valid metric options"),
+            ),
+            public_lobbies: register(
+                &registry,
+                IntGauge::new("rtc_lobby_public_lobbies", "Open lobbies listed publicly")
+                    .expect("valid metric options"),
+            ),
+            messages: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "rtc_lobby_messages_received_total",
+                        "Messages received from hosts and clients",
+                    ),
+                    &["role", "type"],
+                )
+                .expect("valid metric options"),
+            ),
+            errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rtc_lobby_errors_total", "Errors sent to hosts and clients"),
+                    &["error"],
+                )
+                .expect("valid metric options"),
+            ),
+            join_latency: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "rtc_lobby_join_latency_seconds",
+                        "Time between a join request is sent to the host and its answer",
+                    )
+                    .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+                    &["answer"],
+                )
+                .expect("valid metric options"),
+            ),
+            registry,
+        }
+    }
+
+    pub fn message_received(&self, role: Role, message: &UserMessage) {
+        let role = match role {
+            Role::Host => "host",
+            Role::Client => "client",
+        };
+        self.messages
+            .with_label_values(&[role, message.type_name()])
+            .inc();
+    }
+
+    /// Counts the errors among the messages sent to hosts and clients
+    pub fn message_sent(&self, message: &UserMessage) {
+        if let UserMessage::Error { error_type, .. } = message {
+            self.errors.with_label_values(&[error_type.name()]).inc();
+        }
+    }
+
+    /// `answer` is the type of the message of the host
+    pub fn join_answered(&self, answer: &UserMessage, latency: Duration) {
+        self.join_latency
+            .with_label_values(&[answer.type_name()])
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Metrics in the Prometheus text format, with the lobby counts given
+    pub fn encode(&self, lobbies: usize, public_lobbies: usize) -> String {
+        self.lobbies.set(lobbies.try_into().unwrap_or(i64::MAX));
+        self.public_lobbies
+            .set(public_lobbies.try_into().unwrap_or(i64::MAX));
+
+        let mut families = self.registry.gather();
+        families.extend(prometheus::gather());
+
+        let mut buffer = vec![];
+        if let Err(error) = TextEncoder::new().encode(&families, &mut buffer) {
+            tracing::error!(%error, "Could not encode the metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    registry: &Registry,
+    collector: T,
+) -> T {
+    // Only fails if another collector already uses the name
+    if let Err(error) = registry.register(Box::new(collector.clone())) {
+        tracing::error!(%error, "Could not register a metric");
+    }
+    collector
+}
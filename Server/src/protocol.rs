@@ -0,0 +1,193 @@
+//! Versions of the protocol, and the features a connection agreed on with a `hello`.
+//!
+//! Connections that do not start with a `hello` speak version 1, and only get the
+//! messages it had before the handshake existed, without any feature.
+
+use crate::message::{UserMessage, UserMessageError};
+use warp::reply;
+use warp::ws::Message;
+
+/// Version of the protocol spoken by the server
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version still spoken by the server
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Header of the requests to `/api/client` with the version of the protocol they use
+pub const PROTOCOL_HEADER: &str = "rtc-lobby-protocol";
+
+/// Optional parts of the protocol: messages the server only sends to the
+/// connections that know them, or only accepts from them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// `resume-token` messages are sent to the hosts
+    Resume,
+    /// Hosts can stand by and take lobbies, and designate their successor
+    Migration,
+    /// `server-shutdown` messages are sent to the hosts
+    Shutdown,
+}
+
+impl Feature {
+    const ALL: [Feature; 3] = [Feature::Resume, Feature::Migration, Feature::Shutdown];
+
+    /// Name of the feature in the `hello` messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Resume => "resume",
+            Feature::Migration => "migration",
+            Feature::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// What a connection and the server agreed on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    features: Vec<Feature>,
+}
+
+impl Protocol {
+    /// Protocol of the connections without a `hello`
+    pub fn legacy() -> Protocol {
+        Protocol {
+            version: 1,
+            features: vec![],
+        }
+    }
+
+    /// Keeps the features that the server knows.
+    /// Fails if the server does not speak the version
+    pub fn negotiate(version: u32, features: &[String]) -> Result<Protocol, UserMessageError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(UserMessageError::UnsupportedVersion);
+        }
+
+        let features = Feature::ALL
+            .into_iter()
+            .filter(|feature| features.iter().any(|name| name == feature.name()))
+            .collect();
+        Ok(Protocol { version, features })
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// Answer of the server to a `hello`
+    pub fn hello(&self) -> UserMessage {
+        UserMessage::Hello {
+            version: self.version,
+            features: self
+                .features
+                .iter()
+                .map(|feature| feature.name().to_string())
+                .collect(),
+            request_id: None,
+        }
+    }
+
+    /// Serializes a message to send it through a WebSocket of this protocol
+    pub fn encode(&self, message: &UserMessage) -> Message {
+        match self.legacy_error(message) {
+            Some(error) => (&error).into(),
+            None => message.into(),
+        }
+    }
+
+    /// Answer to a request to `/api/client` of this protocol
+    pub fn reply(&self, message: &UserMessage) -> reply::Json {
+        match self.legacy_error(message) {
+            Some(error) => reply::json(&error),
+            None => reply::json(message),
+        }
+    }
+
+    /// Errors of version 1 only have an `errorType`, among the ones it knows
+    fn legacy_error(&self, message: &UserMessage) -> Option<UserMessage> {
+        let UserMessage::Error { error_type, .. } = message else {
+            return None;
+        };
+        if self.version >= 2 {
+            return None;
+        }
+
+        Some(UserMessage::Error {
+            error_type: error_type.legacy(),
+            message: String::new(),
+            field: None,
+            request_id: None,
+        })
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::legacy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate_keeps_the_known_features() {
+        let protocol = Protocol::negotiate(2, &features(&["shutdown", "teleport"])).unwrap();
+        assert_eq!(protocol.version, 2);
+        assert!(protocol.has(Feature::Shutdown));
+        assert!(!protocol.has(Feature::Resume));
+        assert!(!protocol.has(Feature::Migration));
+
+        let UserMessage::Hello { features, .. } = protocol.hello() else {
+            panic!("expected a hello");
+        };
+        assert_eq!(features, ["shutdown"]);
+    }
+
+    #[test]
+    fn negotiate_refuses_unknown_versions() {
+        for version in [0, PROTOCOL_VERSION + 1] {
+            let negotiated = Protocol::negotiate(version, &[]);
+            assert_eq!(negotiated, Err(UserMessageError::UnsupportedVersion));
+        }
+        assert!(Protocol::negotiate(MIN_PROTOCOL_VERSION, &[]).is_ok());
+    }
+
+    #[test]
+    fn legacy_connections_have_no_feature() {
+        let protocol = Protocol::legacy();
+        assert_eq!(protocol.version, 1);
+        assert!(Feature::ALL
+            .into_iter()
+            .all(|feature| !protocol.has(feature)));
+    }
+
+    #[test]
+    fn legacy_errors_only_have_their_type() {
+        let error = UserMessageError::MalformedJson
+            .with_message("expected value")
+            .with_request_id(Some("1".to_string()));
+
+        let legacy = Protocol::legacy().legacy_error(&error).unwrap();
+        let UserMessage::Error {
+            error_type,
+            message,
+            field,
+            request_id,
+        } = legacy
+        else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_type, UserMessageError::InvalidMessage);
+        assert!(message.is_empty() && field.is_none() && request_id.is_none());
+
+        let current = Protocol::negotiate(2, &[]).unwrap();
+        assert!(current.legacy_error(&error).is_none());
+    }
+}
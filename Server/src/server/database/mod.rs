@@ -1,3 +1,54 @@
 mod ram_database;
+mod sled_database;
+
+use super::Lobby;
+use crate::error::Result;
+use crate::message::LobbyDetails;
+use tokio::sync::Mutex;
 
 pub use ram_database::*;
+pub use sled_database::*;
+
+/// Names that only differ by their case belong to the same lobby
+pub fn lobby_key(lobby_name: &str) -> String {
+    lobby_name.to_lowercase()
+}
+
+/// Storage of the open lobbies, indexed by their name.
+///
+/// The lobbies are locked individually, so a database only has to be
+/// borrowed mutably to add, rename or remove them.
+pub trait LobbyDatabase: Send + Sync {
+    /// Fails, giving back the lobby, if its name is already taken or reserved
+    /// with a different key
+    #[allow(clippy::result_large_err)]
+    fn add_lobby(&mut self, lobby: Lobby) -> Result<(), Lobby>;
+
+    fn get_lobby(&self, lobby_name: &str) -> Option<&Mutex<Lobby>>;
+
+    /// Whether the name is taken by an open lobby or a reservation
+    fn contains_lobby(&self, lobby_name: &str) -> bool {
+        self.get_lobby(lobby_name).is_some()
+    }
+
+    /// Fails if the lobby does not exist or the new name is already taken.
+    /// A lobby can change the case of its name
+    #[allow(clippy::result_unit_err)]
+    fn rename_lobby(&mut self, lobby_name: &str, new_name: &str) -> Result<()>;
+
+    /// Lobbies with a reservation key keep their name reserved for a while
+    #[allow(clippy::result_unit_err)]
+    fn remove_lobby(&mut self, lobby_name: &str) -> Result<Lobby>;
+
+    /// Called before the new details are applied to the lobby,
+    /// to keep the public list in sync
+    #[allow(clippy::result_unit_err)]
+    fn update_details(&mut self, lobby_name: &str, details: &LobbyDetails) -> Result<()>;
+
+    fn public_lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_>;
+
+    /// Every open lobby, public or not
+    fn lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_>;
+
+    fn lobby_count(&self) -> usize;
+}
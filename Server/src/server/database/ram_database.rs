@@ -1,18 +1,156 @@
-use std::collections::HashMap;
-
+use super::{lobby_key, LobbyDatabase};
 use crate::error::Result;
-use crate::lobbies::lobby::Lobby;
-use crate::lobbies::LobbyDatabase;
+use crate::message::LobbyDetails;
+use crate::server::{Lobby, SecretHash};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+/// Name of a closed lobby that only its previous host can take
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub key: SecretHash,
+    pub until: SystemTime,
+}
+
+impl Reservation {
+    pub(super) fn is_active(&self) -> bool {
+        SystemTime::now() < self.until
+    }
+}
 
+/// Keeps the lobbies in memory. They are lost when the server stops.
+/// They are indexed by the lowercase of their name, so names are unique whatever their case
 #[derive(Default)]
 pub struct RamDatabase {
+    map: HashMap<String, Mutex<Lobby>>,
+    public_lobbies: HashSet<String>,
+    reservations: HashMap<String, Reservation>,
+    reservation_period: Duration,
+}
+
+impl RamDatabase {
+    pub fn new(reservation_period: Duration) -> RamDatabase {
+        RamDatabase {
+            reservation_period,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn reserve(&mut self, lobby_name: String, reservation: Reservation) {
+        if reservation.is_active() {
+            self.reservations
+                .insert(lobby_key(&lobby_name), reservation);
+        }
+    }
+
+    pub(super) fn reservation(&self, lobby_name: &str) -> Option<&Reservation> {
+        self.reservations
+            .get(&lobby_key(lobby_name))
+            .filter(|reservation| reservation.is_active())
+    }
 }
 
 impl LobbyDatabase for RamDatabase {
-    fn add_lobby(&mut self, lobby: Lobby) -> Result<()> {
-        match self.map.try_insert(lobby.details.lobby_name, lobby) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
+    fn add_lobby(&mut self, lobby: Lobby) -> Result<(), Lobby> {
+        self.reservations
+            .retain(|_, reservation| reservation.is_active());
+
+        let key = lobby_key(&lobby.details().lobby_name);
+        if let Some(reservation) = self.reservations.get(&key) {
+            if lobby.reservation_key() != Some(reservation.key) {
+                return Err(lobby);
+            }
+        }
+
+        match self.map.entry(key) {
+            Entry::Occupied(_) => Err(lobby),
+            Entry::Vacant(entry) => {
+                self.reservations.remove(entry.key());
+                if lobby.is_public() {
+                    self.public_lobbies.insert(entry.key().clone());
+                }
+                entry.insert(Mutex::new(lobby));
+                Ok(())
+            }
         }
     }
+
+    fn get_lobby(&self, lobby_name: &str) -> Option<&Mutex<Lobby>> {
+        self.map.get(&lobby_key(lobby_name))
+    }
+
+    fn contains_lobby(&self, lobby_name: &str) -> bool {
+        self.map.contains_key(&lobby_key(lobby_name)) || self.reservation(lobby_name).is_some()
+    }
+
+    fn rename_lobby(&mut self, lobby_name: &str, new_name: &str) -> Result<()> {
+        let (key, new_key) = (lobby_key(lobby_name), lobby_key(new_name));
+        if key == new_key {
+            return match self.map.contains_key(&key) {
+                true => Ok(()),
+                false => Err(()),
+            };
+        }
+        if self.contains_lobby(new_name) {
+            return Err(());
+        }
+
+        let lobby = self.map.remove(&key).ok_or(())?;
+        self.map.insert(new_key.clone(), lobby);
+
+        if self.public_lobbies.remove(&key) {
+            self.public_lobbies.insert(new_key);
+        }
+
+        Ok(())
+    }
+
+    fn remove_lobby(&mut self, lobby_name: &str) -> Result<Lobby> {
+        let key = lobby_key(lobby_name);
+        let lobby = self.map.remove(&key).ok_or(())?.into_inner();
+        self.public_lobbies.remove(&key);
+
+        if let Some(key) = lobby.reservation_key() {
+            let reservation = Reservation {
+                key,
+                until: SystemTime::now() + self.reservation_period,
+            };
+            self.reserve(lobby_name.to_string(), reservation);
+        }
+
+        Ok(lobby)
+    }
+
+    fn update_details(&mut self, lobby_name: &str, details: &LobbyDetails) -> Result<()> {
+        let key = lobby_key(lobby_name);
+        if !self.map.contains_key(&key) {
+            return Err(());
+        }
+
+        if details.public_lobby {
+            self.public_lobbies.insert(key);
+        } else {
+            self.public_lobbies.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    fn public_lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_> {
+        Box::new(
+            self.public_lobbies
+                .iter()
+                .filter_map(|lobby_name| self.map.get(lobby_name)),
+        )
+    }
+
+    fn lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_> {
+        Box::new(self.map.values())
+    }
+
+    fn lobby_count(&self) -> usize {
+        self.map.len()
+    }
 }
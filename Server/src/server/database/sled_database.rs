@@ -0,0 +1,183 @@
+use super::{lobby_key, LobbyDatabase, RamDatabase, Reservation};
+use crate::error::Result;
+use crate::message::LobbyDetails;
+use crate::server::{Lobby, SecretHash};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+struct LobbyRecord {
+    details: LobbyDetails,
+    reservation_key: Option<SecretHash>,
+    /// Unix time in seconds until the name stays reserved.
+    /// It is `None` while the lobby is open.
+    reserved_until: Option<u64>,
+}
+
+/// Keeps the lobbies in a [`RamDatabase`] and stores their details on disk,
+/// under the [`lobby_key`] of their name.
+///
+/// When the server restarts, the lobbies that had a reservation key are
+/// reserved again, so their hosts can reclaim them after reconnecting.
+pub struct SledDatabase {
+    ram: RamDatabase,
+    tree: sled::Db,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl SledDatabase {
+    pub fn open(path: impl AsRef<Path>, reservation_period: Duration) -> sled::Result<Self> {
+        let tree = sled::open(path)?;
+        let mut ram = RamDatabase::new(reservation_period);
+
+        // Lobbies that were open when the server stopped get a new reservation period
+        let restart_reservation = SystemTime::now() + reservation_period;
+
+        for entry in tree.iter() {
+            let (key, record) = entry?;
+            // Saved again below, older databases stored the names as they were
+            tree.remove(key)?;
+
+            let Ok(mut record) = serde_json::from_slice::<LobbyRecord>(&record) else {
+                continue;
+            };
+            let Some(reservation_key) = record.reservation_key else {
+                continue;
+            };
+            let until = match record.reserved_until {
+                Some(until) => UNIX_EPOCH + Duration::from_secs(until),
+                None => restart_reservation,
+            };
+            let reservation = Reservation {
+                key: reservation_key,
+                until,
+            };
+            if !reservation.is_active() {
+                continue;
+            }
+
+            let lobby_name = record.details.lobby_name.clone();
+            record.reserved_until = Some(unix_time(until));
+            ram.reserve(lobby_name.clone(), reservation);
+            Self::save(&tree, &lobby_name, &record);
+        }
+
+        Ok(SledDatabase { ram, tree })
+    }
+
+    fn load(tree: &sled::Db, lobby_name: &str) -> Option<LobbyRecord> {
+        let record = match tree.get(lobby_key(lobby_name).as_bytes()) {
+            Ok(record) => record?,
+            Err(error) => {
+                tracing::error!(%lobby_name, %error, "Could not read lobby from the database");
+                return None;
+            }
+        };
+
+        serde_json::from_slice(&record).ok()
+    }
+
+    fn save(tree: &sled::Db, lobby_name: &str, record: &LobbyRecord) {
+        let Ok(record) = serde_json::to_vec(record) else {
+            tracing::error!(%lobby_name, "Could not serialize lobby");
+            return;
+        };
+
+        if let Err(error) = tree.insert(lobby_key(lobby_name).as_bytes(), record) {
+            tracing::error!(%lobby_name, %error, "Could not store lobby in the database");
+        }
+    }
+
+    /// Modifies the stored record of a lobby, if there is one
+    fn store(tree: &sled::Db, lobby_name: &str, update: impl FnOnce(&mut LobbyRecord)) {
+        if let Some(mut record) = Self::load(tree, lobby_name) {
+            update(&mut record);
+            Self::save(tree, lobby_name, &record);
+        }
+    }
+
+    fn delete(&self, lobby_name: &str) {
+        if let Err(error) = self.tree.remove(lobby_key(lobby_name).as_bytes()) {
+            tracing::error!(%lobby_name, %error, "Could not delete lobby from the database");
+        }
+    }
+}
+
+impl LobbyDatabase for SledDatabase {
+    fn add_lobby(&mut self, lobby: Lobby) -> Result<(), Lobby> {
+        let record = LobbyRecord {
+            details: lobby.details().clone(),
+            reservation_key: lobby.reservation_key(),
+            reserved_until: None,
+        };
+
+        self.ram.add_lobby(lobby)?;
+        Self::save(&self.tree, &record.details.lobby_name, &record);
+        Ok(())
+    }
+
+    fn get_lobby(&self, lobby_name: &str) -> Option<&Mutex<Lobby>> {
+        self.ram.get_lobby(lobby_name)
+    }
+
+    fn contains_lobby(&self, lobby_name: &str) -> bool {
+        self.ram.contains_lobby(lobby_name)
+    }
+
+    fn rename_lobby(&mut self, lobby_name: &str, new_name: &str) -> Result<()> {
+        self.ram.rename_lobby(lobby_name, new_name)?;
+
+        if let Some(mut record) = Self::load(&self.tree, lobby_name) {
+            self.delete(lobby_name);
+            record.details.lobby_name = new_name.to_string();
+            Self::save(&self.tree, new_name, &record);
+        }
+
+        Ok(())
+    }
+
+    fn remove_lobby(&mut self, lobby_name: &str) -> Result<Lobby> {
+        let lobby = self.ram.remove_lobby(lobby_name)?;
+
+        match self.ram.reservation(lobby_name) {
+            Some(reservation) => {
+                let until = unix_time(reservation.until);
+                Self::store(&self.tree, lobby_name, |record| {
+                    record.reserved_until = Some(until);
+                });
+            }
+            None => self.delete(lobby_name),
+        }
+
+        Ok(lobby)
+    }
+
+    fn update_details(&mut self, lobby_name: &str, details: &LobbyDetails) -> Result<()> {
+        self.ram.update_details(lobby_name, details)?;
+
+        Self::store(&self.tree, lobby_name, |record| {
+            record.details = details.clone();
+        });
+
+        Ok(())
+    }
+
+    fn public_lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_> {
+        self.ram.public_lobbies()
+    }
+
+    fn lobbies(&self) -> Box<dyn Iterator<Item = &Mutex<Lobby>> + Send + '_> {
+        self.ram.lobbies()
+    }
+
+    fn lobby_count(&self) -> usize {
+        self.ram.lobby_count()
+    }
+}
@@ -0,0 +1,286 @@
+use super::Lobby;
+use crate::message::{LobbyDetails, LobbySort};
+use std::cmp::Ordering;
+use std::time::UNIX_EPOCH;
+
+/// A public lobby to list, with the value it is sorted by
+pub struct ListedLobby {
+    key: u64,
+    details: LobbyDetails,
+}
+
+impl ListedLobby {
+    pub fn new(lobby: &Lobby, sort: LobbySort) -> ListedLobby {
+        let key = match sort {
+            LobbySort::Name => 0,
+            LobbySort::Capacity => lobby.capacity().into(),
+            LobbySort::ClientCount => lobby.details().client_count.into(),
+            LobbySort::CreationTime => lobby
+                .created()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |created| {
+                    created.as_millis().try_into().unwrap_or(u64::MAX)
+                }),
+        };
+
+        ListedLobby {
+            key,
+            details: lobby.details().clone(),
+        }
+    }
+
+    /// Names are unique, so they break the ties and the order is stable between pages
+    fn cmp(&self, key: u64, lobby_name: &str) -> Ordering {
+        (self.key, self.details.lobby_name.as_str()).cmp(&(key, lobby_name))
+    }
+}
+
+/// Position after the last lobby of a page: `<sort>:<key>:<name>`.
+/// Clients only send it back as they got it
+struct Cursor {
+    sort: LobbySort,
+    key: u64,
+    lobby_name: String,
+}
+
+impl Cursor {
+    fn parse(cursor: &str) -> Option<Cursor> {
+        let mut parts = cursor.splitn(3, ':');
+        let sort = match parts.next()? {
+            "name" => LobbySort::Name,
+            "capacity" => LobbySort::Capacity,
+            "clientCount" => LobbySort::ClientCount,
+            "creationTime" => LobbySort::CreationTime,
+            _ => return None,
+        };
+        let key = parts.next()?.parse().ok()?;
+        let lobby_name = parts.next()?.to_string();
+
+        Some(Cursor {
+            sort,
+            key,
+            lobby_name,
+        })
+    }
+
+    fn format(sort: LobbySort, lobby: &ListedLobby) -> String {
+        format!("{}:{}:{}", sort.name(), lobby.key, lobby.details.lobby_name)
+    }
+}
+
+/// One page of the lobbies
+pub struct Page {
+    pub lobbies: Vec<LobbyDetails>,
+    /// Lobbies in every page
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
+/// Sorts the lobbies and takes the ones after the cursor.
+/// Fails if the cursor is invalid or was made for another sort
+pub fn page(
+    mut lobbies: Vec<ListedLobby>,
+    sort: LobbySort,
+    descending: bool,
+    cursor: Option<&str>,
+    maximum_lobbies: usize,
+) -> Result<Page, ()> {
+    let order = |ordering: Ordering| match descending {
+        true => ordering.reverse(),
+        false => ordering,
+    };
+    lobbies.sort_unstable_by(|a, b| order(a.cmp(b.key, &b.details.lobby_name)));
+    let total = lobbies.len();
+
+    let start = match cursor {
+        None => 0,
+        Some(cursor) => {
+            let cursor = Cursor::parse(cursor).ok_or(())?;
+            if cursor.sort != sort {
+                return Err(());
+            }
+            lobbies.partition_point(|lobby| {
+                order(lobby.cmp(cursor.key, &cursor.lobby_name)) != Ordering::Greater
+            })
+        }
+    };
+
+    let end = start.saturating_add(maximum_lobbies).min(total);
+    let next_cursor = match end < total && end > start {
+        true => Some(Cursor::format(sort, &lobbies[end - 1])),
+        false => None,
+    };
+
+    let lobbies = lobbies
+        .drain(start..end)
+        .map(|lobby| lobby.details)
+        .collect();
+    Ok(Page {
+        lobbies,
+        total,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobbies(lobbies: &[(u64, &str)]) -> Vec<ListedLobby> {
+        lobbies
+            .iter()
+            .map(|&(key, lobby_name)| ListedLobby {
+                key,
+                details: LobbyDetails {
+                    lobby_name: lobby_name.to_string(),
+                    public_lobby: true,
+                    max_clients: 8,
+                    client_count: 0,
+                    has_password: false,
+                    metadata: Default::default(),
+                },
+            })
+            .collect()
+    }
+
+    fn names(page: &Page) -> Vec<&str> {
+        page.lobbies
+            .iter()
+            .map(|details| details.lobby_name.as_str())
+            .collect()
+    }
+
+    /// Follows the cursors until the last page
+    fn all_pages(
+        listed: &[(u64, &str)],
+        sort: LobbySort,
+        descending: bool,
+        maximum_lobbies: usize,
+    ) -> Vec<Vec<String>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let page = page(
+                lobbies(listed),
+                sort,
+                descending,
+                cursor.as_deref(),
+                maximum_lobbies,
+            )
+            .unwrap();
+            assert_eq!(page.total, listed.len());
+            pages.push(names(&page).into_iter().map(str::to_string).collect());
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                return pages;
+            }
+        }
+    }
+
+    #[test]
+    fn pages_follow_the_sort() {
+        let listed = [(3, "c"), (1, "a"), (2, "b")];
+        let page = page(lobbies(&listed), LobbySort::Capacity, false, None, 10).unwrap();
+        assert_eq!(names(&page), ["a", "b", "c"]);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn cursors_walk_every_lobby_once() {
+        let listed = [(2, "d"), (1, "b"), (2, "a"), (1, "c"), (3, "e")];
+        assert_eq!(
+            all_pages(&listed, LobbySort::ClientCount, false, 2),
+            [vec!["b", "c"], vec!["a", "d"], vec!["e"]]
+        );
+        assert_eq!(
+            all_pages(&listed, LobbySort::ClientCount, true, 2),
+            [vec!["e", "d"], vec!["a", "c"], vec!["b"]]
+        );
+    }
+
+    #[test]
+    fn cursors_survive_removed_lobbies() {
+        let listed = [(1, "a"), (2, "b"), (3, "c"), (4, "d")];
+        let first = page(lobbies(&listed), LobbySort::Capacity, false, None, 2).unwrap();
+        let cursor = first.next_cursor.unwrap();
+
+        let remaining = [(1, "a"), (3, "c"), (4, "d")];
+        let second = page(
+            lobbies(&remaining),
+            LobbySort::Capacity,
+            false,
+            Some(&cursor),
+            2,
+        )
+        .unwrap();
+        assert_eq!(names(&second), ["c", "d"]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn names_can_contain_colons() {
+        let listed = [(0, "a:1"), (0, "a:2"), (0, "b::")];
+        assert_eq!(
+            all_pages(&listed, LobbySort::Name, false, 1),
+            [vec!["a:1"], vec!["a:2"], vec!["b::"]]
+        );
+    }
+
+    #[test]
+    fn cursors_are_bound_to_their_sort() {
+        let listed = [(1, "a"), (2, "b"), (3, "c")];
+        let first = page(lobbies(&listed), LobbySort::Capacity, false, None, 1).unwrap();
+        let cursor = first.next_cursor.unwrap();
+
+        let other_sort = page(
+            lobbies(&listed),
+            LobbySort::ClientCount,
+            false,
+            Some(&cursor),
+            1,
+        );
+        assert!(other_sort.is_err());
+        assert!(page(lobbies(&listed), LobbySort::Name, false, Some("name"), 1).is_err());
+        assert!(page(
+            lobbies(&listed),
+            LobbySort::Name,
+            false,
+            Some("name:x:a"),
+            1
+        )
+        .is_err());
+        assert!(page(
+            lobbies(&listed),
+            LobbySort::Name,
+            false,
+            Some("size:0:a"),
+            1
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn empty_pages_only_count_the_lobbies() {
+        let listed = [(1, "a"), (2, "b")];
+        let page = page(lobbies(&listed), LobbySort::Capacity, false, None, 0).unwrap();
+        assert!(page.lobbies.is_empty());
+        assert_eq!(page.total, 2);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn cursor_after_the_last_lobby_gives_an_empty_page() {
+        let listed = [(1, "a"), (2, "b")];
+        let page = page(
+            lobbies(&listed),
+            LobbySort::Capacity,
+            false,
+            Some("capacity:9:z"),
+            5,
+        )
+        .unwrap();
+        assert!(page.lobbies.is_empty());
+        assert_eq!(page.next_cursor, None);
+    }
+}
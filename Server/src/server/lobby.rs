@@ -1,24 +1,102 @@
-use super::HostChannel;
-use crate::log;
+use super::secret::{random_secret, SaltedHash};
+use super::{HostChannel, SecretHash};
 use crate::message::*;
+use crate::metrics::Metrics;
+use crate::protocol::{Feature, Protocol};
 use futures_util::*;
 use std::collections::HashMap;
-use tokio::sync::oneshot;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Messages from the host to a client connected through a WebSocket
+pub type ClientChannel = mpsc::UnboundedSender<UserMessage>;
+
+/// Lets a connection of the host follow its lobby when it is renamed or closed by someone else
+#[derive(Debug, Clone)]
+pub struct LobbyHandle {
+    name: watch::Receiver<String>,
+    connection: u32,
+}
+
+impl LobbyHandle {
+    /// Current name of the lobby
+    pub fn name(&self) -> String {
+        self.name.borrow().clone()
+    }
+
+    /// Whether the lobby is still in the database
+    pub fn is_open(&self) -> bool {
+        self.name.has_changed().is_ok()
+    }
+}
+
+/// A join request sent to the host
+struct JoinRequest {
+    answer: oneshot::Sender<UserMessage>,
+    sent: Instant,
+    /// Sent again if the host resumes the lobby from a new connection
+    offer: String,
+}
+
+/// A connection waiting to become the host of the lobby
+struct Candidate {
+    connection: u32,
+    channel: HostChannel,
+    protocol: Protocol,
+    address: Option<IpAddr>,
+}
 
 pub struct Lobby {
     host_channel: HostChannel,
+    host_protocol: Protocol,
+    host_connection: u32,
+    host_connected: bool,
+    /// Counts the connections of hosts and candidates, to tell them apart
+    past_connection: u32,
+    candidates: Vec<Candidate>,
+    successor: Option<u32>,
     details: LobbyDetails,
-    join_requests: HashMap<u32, oneshot::Sender<UserMessage>>,
+    created: SystemTime,
+    name: watch::Sender<String>,
+    reservation_key: Option<SecretHash>,
+    resume_token: Option<SecretHash>,
+    password: Option<SaltedHash>,
+    host_address: Option<IpAddr>,
+    join_requests: HashMap<u32, JoinRequest>,
+    ice_channels: HashMap<u32, ClientChannel>,
     past_join_request_id: u32,
+    metrics: Arc<Metrics>,
 }
 
 impl Lobby {
-    pub fn create(host_channel: HostChannel, details: LobbyDetails) -> Lobby {
+    /// The join answers and the errors sent to the host are counted in the metrics
+    pub fn create(
+        host_channel: HostChannel,
+        details: LobbyDetails,
+        metrics: Arc<Metrics>,
+    ) -> Lobby {
+        let (name, _) = watch::channel(details.lobby_name.clone());
         Lobby {
             host_channel,
+            host_protocol: Protocol::legacy(),
+            host_connection: 0,
+            host_connected: true,
+            past_connection: 0,
+            candidates: vec![],
+            successor: None,
+            name,
             details,
+            created: SystemTime::now(),
+            reservation_key: None,
+            resume_token: None,
+            password: None,
+            host_address: None,
             join_requests: HashMap::new(),
+            ice_channels: HashMap::new(),
             past_join_request_id: 0,
+            metrics,
         }
     }
 
@@ -26,23 +104,343 @@ impl Lobby {
         &self.details
     }
 
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Handle for the current connection of the host
+    pub fn handle(&self) -> LobbyHandle {
+        LobbyHandle {
+            name: self.name.subscribe(),
+            connection: self.host_connection,
+        }
+    }
+
+    /// Whether the handle was given by this lobby, and not by a closed lobby that had
+    /// the same name. Connection ids are only unique inside a lobby
+    fn has_given(&self, handle: &LobbyHandle) -> bool {
+        handle.name.same_channel(&self.name.subscribe())
+    }
+
+    /// Whether the handle belongs to the current connection of the host
+    pub fn is_hosted_by(&self, handle: &LobbyHandle) -> bool {
+        self.host_connected && self.has_given(handle) && handle.connection == self.host_connection
+    }
+
+    /// Returns if the handle belonged to the current connection of the host
+    pub fn disconnect_host(&mut self, handle: &LobbyHandle) -> bool {
+        let hosted = self.is_hosted_by(handle);
+        if hosted {
+            self.host_connected = false;
+        }
+        hosted
+    }
+
+    /// Whether the host has not come back since the connection of the handle dropped
+    pub fn is_abandoned_by(&self, handle: &LobbyHandle) -> bool {
+        !self.host_connected && self.has_given(handle) && handle.connection == self.host_connection
+    }
+
+    pub fn set_resume_token(&mut self, token: &str) {
+        self.resume_token = Some(SecretHash::new(token));
+    }
+
+    pub fn accepts_resume_token(&self, token: &str) -> bool {
+        self.resume_token.is_some_and(|hash| hash.matches(token))
+    }
+
+    /// Only sent to hosts that know the resume feature
+    pub async fn send_resume_token(&mut self, resume_token: String) -> Result<(), ()> {
+        if !self.host_protocol.has(Feature::Resume) {
+            return Ok(());
+        }
+        self.send_message_to_host(&UserMessage::ResumeToken { resume_token })
+            .await
+    }
+
+    /// Protocol agreed on with the current connection of the host
+    pub fn host_protocol(&self) -> &Protocol {
+        &self.host_protocol
+    }
+
+    pub fn set_host_protocol(&mut self, protocol: Protocol) {
+        self.host_protocol = protocol;
+    }
+
+    /// Moves the lobby to a new connection of the host, and sends it the details and
+    /// the join requests still waiting for an answer. Gives back the previous connection
+    pub async fn resume(
+        &mut self,
+        host_channel: HostChannel,
+        protocol: Protocol,
+        address: Option<IpAddr>,
+        request_id: Option<String>,
+    ) -> Result<HostChannel, ()> {
+        let connection = self.new_connection();
+        self.host_address = address;
+        self.connect_host(host_channel, protocol, connection, None, request_id)
+            .await
+    }
+
+    fn new_connection(&mut self) -> u32 {
+        self.past_connection = self.past_connection.wrapping_add(1);
+        self.past_connection
+    }
+
+    async fn connect_host(
+        &mut self,
+        host_channel: HostChannel,
+        protocol: Protocol,
+        connection: u32,
+        resume_token: Option<String>,
+        request_id: Option<String>,
+    ) -> Result<HostChannel, ()> {
+        let previous = std::mem::replace(&mut self.host_channel, host_channel);
+        self.host_protocol = protocol;
+        self.host_connection = connection;
+        self.host_connected = true;
+
+        if let Some(resume_token) = resume_token {
+            self.set_resume_token(&resume_token);
+            self.send_resume_token(resume_token).await?;
+        }
+
+        self.expire_abandoned_join_requests().await;
+
+        let details = UserMessage::LobbyDetails {
+            details: self.details.clone(),
+            request_id,
+        };
+        self.send_message_to_host(&details).await?;
+
+        let mut ids: Vec<u32> = self.join_requests.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let join_request = UserMessage::JoinRequest {
+                lobby_name: Some(self.details.lobby_name.clone()),
+                offer: self.join_requests[&id].offer.clone(),
+                id: Some(id),
+                password: None,
+            };
+            self.send_message_to_host(&join_request).await?;
+        }
+
+        Ok(previous)
+    }
+
+    /// Adds a connection that stands by to host the lobby when its host leaves.
+    /// It is sent its id, with which the host can designate it as its successor
+    pub async fn add_candidate(
+        &mut self,
+        mut channel: HostChannel,
+        protocol: Protocol,
+        address: Option<IpAddr>,
+        request_id: Option<String>,
+    ) -> Result<LobbyHandle, ()> {
+        let connection = self.new_connection();
+        let accepted = UserMessage::StandbyAccepted {
+            candidate_id: connection,
+            request_id,
+        };
+        if channel.send(protocol.encode(&accepted)).await.is_err() {
+            return Err(());
+        }
+
+        self.candidates.push(Candidate {
+            connection,
+            channel,
+            protocol,
+            address,
+        });
+        Ok(LobbyHandle {
+            name: self.name.subscribe(),
+            connection,
+        })
+    }
+
+    pub fn candidate_count(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Whether the handle belongs to a connection standing by to host the lobby
+    pub fn is_candidate(&self, handle: &LobbyHandle) -> bool {
+        self.has_given(handle)
+            && self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.connection == handle.connection)
+    }
+
+    pub fn remove_candidate(&mut self, handle: &LobbyHandle) {
+        if !self.has_given(handle) {
+            return;
+        }
+        self.candidates
+            .retain(|candidate| candidate.connection != handle.connection);
+    }
+
+    /// `None` lets the server pick the candidate that stood by first
+    #[allow(clippy::result_unit_err)]
+    pub fn designate_successor(&mut self, candidate_id: Option<u32>) -> Result<(), ()> {
+        if let Some(candidate_id) = candidate_id {
+            let found = self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.connection == candidate_id);
+            if !found {
+                return Err(());
+            }
+        }
+
+        self.successor = candidate_id;
+        Ok(())
+    }
+
+    /// Gives the lobby to the designated successor, or else to the first candidate,
+    /// as if it resumed it with a new resume token. Candidates that can not be reached
+    /// are skipped. Fails if there is no candidate left, and then the lobby is still
+    /// abandoned by the previous host
+    pub async fn migrate_host(&mut self) -> Result<(), ()> {
+        let previous_connection = self.host_connection;
+        while !self.candidates.is_empty() {
+            let designated = self.successor.take().and_then(|successor| {
+                self.candidates
+                    .iter()
+                    .position(|candidate| candidate.connection == successor)
+            });
+            let candidate = self.candidates.remove(designated.unwrap_or(0));
+
+            let resume_token = random_secret();
+            let connected = self
+                .connect_host(
+                    candidate.channel,
+                    candidate.protocol,
+                    candidate.connection,
+                    Some(resume_token),
+                    None,
+                )
+                .await;
+            // The connection of the previous host is closed when dropped
+            if connected.is_ok() {
+                self.host_address = candidate.address;
+                return Ok(());
+            }
+
+            tracing::info!(
+                lobby_name = %self.details.lobby_name,
+                candidate_id = candidate.connection,
+                "Candidate host can not be reached"
+            );
+            self.host_connected = false;
+        }
+
+        self.host_connection = previous_connection;
+        Err(())
+    }
+
+    /// Has to be called while the database is locked, when the lobby is renamed in it
+    pub fn notify_rename(&self, lobby_name: &str) {
+        self.name.send_replace(lobby_name.to_string());
+    }
+
+    /// Drops the lobby, closing the handles of the host and the candidates, and gives
+    /// back their connections
+    pub fn into_host_channels(self) -> Vec<HostChannel> {
+        let candidates = self
+            .candidates
+            .into_iter()
+            .map(|candidate| candidate.channel);
+        std::iter::once(self.host_channel)
+            .chain(candidates)
+            .collect()
+    }
+
+    /// Key the host has to present to reclaim the lobby name once it is closed
+    pub fn reservation_key(&self) -> Option<SecretHash> {
+        self.reservation_key
+    }
+
+    pub fn set_reservation_key(&mut self, key: &str) {
+        self.reservation_key = Some(SecretHash::new(key));
+    }
+
+    pub fn host_address(&self) -> Option<IpAddr> {
+        self.host_address
+    }
+
+    pub fn set_host_address(&mut self, address: IpAddr) {
+        self.host_address = Some(address);
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password = Some(SaltedHash::new(password));
+        self.details.has_password = true;
+    }
+
+    /// Lobbies without a password accept any client
+    pub fn accepts_password(&self, password: Option<&str>) -> bool {
+        match (self.password, password) {
+            (None, _) => true,
+            (Some(hash), Some(password)) => hash.matches(password),
+            (Some(_), None) => false,
+        }
+    }
+
     async fn send_message_to_host(&mut self, message: &UserMessage) -> Result<(), ()> {
-        if self.host_channel.send(message.into()).await.is_ok() {
+        self.metrics.message_sent(message);
+        let message = self.host_protocol.encode(message);
+        if self.host_channel.send(message).await.is_ok() {
             Ok(())
         } else {
             Err(())
         }
     }
 
-    pub async fn send_error_to_host(&mut self, error: UserMessageError) {
-        let _ = self.host_channel.send(error.into()).await;
+    pub async fn send_error_to_host(&mut self, error: impl Into<UserMessage>) {
+        let _ = self.send_message_to_host(&error.into()).await;
+    }
+
+    /// Only sent to hosts that know the shutdown feature
+    pub async fn send_shutdown_to_host(&mut self, grace_period: Duration) {
+        if !self.host_protocol.has(Feature::Shutdown) {
+            return;
+        }
+
+        let shutdown = UserMessage::ServerShutdown {
+            grace_period: grace_period.as_secs(),
+        };
+        let _ = self.send_message_to_host(&shutdown).await;
+    }
+
+    /// Free places, counting the clients that are still joining
+    pub fn capacity(&self) -> u16 {
+        let pending_joins = u16::try_from(self.join_requests.len()).unwrap_or(u16::MAX);
+        self.details.capacity().saturating_sub(pending_joins)
+    }
+
+    /// Number of join requests whose client is waiting for an answer of the host
+    pub fn pending_joins(&self) -> usize {
+        self.join_requests
+            .values()
+            .filter(|join_request| !join_request.answer.is_closed())
+            .count()
     }
 
-    pub async fn update_details(&mut self, details: LobbyDetails) -> Result<(), ()> {
-        if self.details != details {
+    /// The host can report clients that left, but the joins are counted by the server.
+    /// The details are sent back if they changed, or if the host waits for an answer
+    pub async fn update_details(
+        &mut self,
+        mut details: LobbyDetails,
+        request_id: Option<String>,
+    ) -> Result<(), ()> {
+        details.client_count = details.client_count.min(self.details.client_count);
+        details.has_password = self.password.is_some();
+
+        if self.details != details || request_id.is_some() {
             self.details = details;
             let update_message = &UserMessage::LobbyDetails {
                 details: self.details.clone(),
+                request_id,
             };
             self.send_message_to_host(update_message).await
         } else {
@@ -50,39 +448,242 @@ impl Lobby {
         }
     }
 
+    /// Returns the id of the join request and where the invitation will arrive
     pub async fn request_invitation(
         &mut self,
         offer: String,
-    ) -> Result<oneshot::Receiver<UserMessage>, ()> {
-        log::user_action!("Requesting invitation to host");
+        ice_channel: Option<ClientChannel>,
+    ) -> Result<(u32, oneshot::Receiver<UserMessage>), UserMessageError> {
+        self.expire_abandoned_join_requests().await;
+        self.ice_channels.retain(|_, channel| !channel.is_closed());
+
+        if self.capacity() == 0 {
+            return Err(UserMessageError::LobbyFull);
+        }
 
         self.past_join_request_id += 1;
-        self.send_message_to_host(&UserMessage::JoinRequest {
-            lobby_name: Some(self.details.lobby_name.clone()),
-            offer,
-            id: Some(self.past_join_request_id),
-        })
-        .await?;
+
+        // While the host is away, the request is sent when it resumes the lobby
+        if self.host_connected {
+            self.send_message_to_host(&UserMessage::JoinRequest {
+                lobby_name: Some(self.details.lobby_name.clone()),
+                offer: offer.clone(),
+                id: Some(self.past_join_request_id),
+                password: None,
+            })
+            .await
+            .map_err(|()| UserMessageError::LobbyNotFound)?;
+
+            tracing::info!(
+                lobby_name = %self.details.lobby_name,
+                id = self.past_join_request_id,
+                "Sent join request to host"
+            );
+        }
 
         let (send_invitation, receive_invitation) = oneshot::channel();
+        let join_request = JoinRequest {
+            answer: send_invitation,
+            sent: Instant::now(),
+            offer,
+        };
         self.join_requests
-            .insert(self.past_join_request_id, send_invitation);
-        Ok(receive_invitation)
+            .insert(self.past_join_request_id, join_request);
+        if let Some(ice_channel) = ice_channel {
+            self.ice_channels
+                .insert(self.past_join_request_id, ice_channel);
+        }
+        Ok((self.past_join_request_id, receive_invitation))
     }
 
-    pub fn send_invitation(&mut self, answer: String, id: u32) -> Result<(), ()> {
-        if let Some(invitation_receiver) = self.join_requests.remove(&id) {
-            invitation_receiver
-                .send(UserMessage::JoinInvitation {
-                    answer,
-                    id: Some(id),
+    /// Forgets a join request that the host did not answer in time
+    pub async fn expire_join_request(&mut self, id: u32) {
+        if self.join_requests.remove(&id).is_some() {
+            tracing::info!(lobby_name = %self.details.lobby_name, id, "Join request expired");
+            let _ = self
+                .send_message_to_host(&UserMessage::JoinExpired {
+                    id,
+                    request_id: None,
                 })
-                .map_err(|_| ())
-        } else {
-            Err(())
+                .await;
         }
     }
 
+    /// Expires the join requests whose client is no longer waiting
+    async fn expire_abandoned_join_requests(&mut self) {
+        let abandoned: Vec<u32> = self
+            .join_requests
+            .iter()
+            .filter(|(_, join_request)| join_request.answer.is_closed())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in abandoned {
+            self.expire_join_request(id).await;
+        }
+    }
+
+    /// Sends the answer of the host to the waiting client.
+    /// Returns if it has been delivered, and only fails if the host can not be reached.
+    async fn answer_join_request(
+        &mut self,
+        id: u32,
+        answer: UserMessage,
+        request_id: Option<String>,
+    ) -> Result<bool, ()> {
+        // Expired requests have already been notified to the host
+        let Some(join_request) = self.join_requests.remove(&id) else {
+            tracing::warn!(
+                lobby_name = %self.details.lobby_name,
+                id,
+                "Join answer does not match any pending join request"
+            );
+            let error = UserMessageError::InvalidField
+                .with_field("id", "The id does not match any pending join request")
+                .with_request_id(request_id);
+            self.send_error_to_host(error).await;
+            return Ok(false);
+        };
+
+        let latency = join_request.sent.elapsed();
+        self.metrics.join_answered(&answer, latency);
+
+        if join_request.answer.send(answer).is_err() {
+            tracing::info!(
+                lobby_name = %self.details.lobby_name,
+                id,
+                "Join answer arrived after the request expired"
+            );
+            let expired = UserMessage::JoinExpired { id, request_id };
+            self.send_message_to_host(&expired).await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Only fails if the host can not be reached. The new details answer the request
+    pub async fn send_invitation(
+        &mut self,
+        answer: String,
+        id: u32,
+        request_id: Option<String>,
+    ) -> Result<(), ()> {
+        let invitation = UserMessage::JoinInvitation {
+            answer,
+            id: Some(id),
+            request_id: None,
+        };
+
+        if !self
+            .answer_join_request(id, invitation, request_id.clone())
+            .await?
+        {
+            return Ok(());
+        }
+
+        let mut details = self.details.clone();
+        details.client_count = details.client_count.saturating_add(1);
+        self.details.client_count = details.client_count;
+        self.send_message_to_host(&UserMessage::LobbyDetails {
+            details,
+            request_id,
+        })
+        .await
+    }
+
+    /// Only fails if the host can not be reached
+    pub async fn send_rejection(
+        &mut self,
+        id: u32,
+        reason: String,
+        code: Option<String>,
+        request_id: Option<String>,
+    ) -> Result<(), ()> {
+        let rejection = UserMessage::JoinRejection {
+            id: Some(id),
+            reason,
+            code,
+            request_id: None,
+        };
+
+        if self
+            .answer_join_request(id, rejection, request_id.clone())
+            .await?
+        {
+            self.acknowledge(request_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the host that its message with this `requestId` has been handled.
+    /// Only fails if the host can not be reached
+    pub async fn acknowledge(&mut self, request_id: Option<String>) -> Result<(), ()> {
+        let Some(request_id) = request_id else {
+            return Ok(());
+        };
+        self.send_message_to_host(&UserMessage::Ack { request_id })
+            .await
+    }
+
+    pub async fn send_ice_candidate_to_host(
+        &mut self,
+        id: u32,
+        candidate: String,
+    ) -> Result<(), UserMessageError> {
+        if !self.ice_channels.contains_key(&id) {
+            return Err(UserMessageError::InvalidMessage);
+        }
+
+        let ice_candidate = UserMessage::IceCandidate {
+            candidate,
+            id: Some(id),
+            request_id: None,
+        };
+        self.send_message_to_host(&ice_candidate)
+            .await
+            .map_err(|()| UserMessageError::LobbyNotFound)
+    }
+
+    pub async fn send_ice_candidate_to_client(
+        &mut self,
+        id: u32,
+        candidate: String,
+        request_id: Option<String>,
+    ) {
+        let Some(ice_channel) = self.ice_channels.get(&id) else {
+            tracing::warn!(
+                lobby_name = %self.details.lobby_name,
+                id,
+                "Ice candidate does not match any client WebSocket"
+            );
+            let error = UserMessageError::InvalidField
+                .with_field("id", "The id does not match any client WebSocket")
+                .with_request_id(request_id);
+            self.send_error_to_host(error).await;
+            return;
+        };
+
+        let ice_candidate = UserMessage::IceCandidate {
+            candidate,
+            id: Some(id),
+            request_id: None,
+        };
+        if ice_channel.send(ice_candidate).is_err() {
+            self.ice_channels.remove(&id);
+            let error = UserMessageError::InvalidField
+                .with_field("id", "The client WebSocket is closed")
+                .with_request_id(request_id);
+            self.send_error_to_host(error).await;
+            return;
+        }
+        let _ = self.acknowledge(request_id).await;
+    }
+
+    pub fn close_ice_channel(&mut self, id: u32) {
+        self.ice_channels.remove(&id);
+    }
+
     pub fn is_public(&self) -> bool {
         self.details.public_lobby
     }
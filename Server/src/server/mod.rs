@@ -1,296 +1,2155 @@
+mod database;
+mod listing;
 mod lobby;
 mod random_words;
+mod rate_limit;
+mod secret;
+mod validation;
 
 use self::random_words::random_word;
-use crate::log;
 use crate::message::*;
+use crate::metrics::{Metrics, Role};
+use crate::protocol::{Feature, Protocol, PROTOCOL_HEADER};
+pub use database::*;
 use futures_util::stream::SplitSink;
 use futures_util::*;
-use lobby::*;
-use std::collections::{HashMap, HashSet};
-use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use listing::ListedLobby;
+pub use lobby::{ClientChannel, Lobby, LobbyHandle};
+use rate_limit::RateLimiter;
+pub use rate_limit::{RateLimitedAction, RateLimits};
+use secret::random_secret;
+pub use secret::SecretHash;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, MutexGuard, RwLock};
+use tokio::time;
+pub use validation::{CharacterClass, NameRules, Normalization};
+use warp::reply;
 use warp::ws::{Message, WebSocket};
 
 pub type HostChannel = SplitSink<WebSocket, Message>;
 
-pub type LoobiesMap = HashMap<String, Mutex<Lobby>>;
+/// Random names tried for a lobby created without one
+const RANDOM_NAME_ATTEMPTS: usize = 100;
+
+/// State of a lobby shown to the administrators
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyInfo {
+    pub details: LobbyDetails,
+    pub pending_joins: usize,
+    pub host_address: Option<IpAddr>,
+}
+
+/// A join request waiting for the answer of the host
+pub struct PendingJoin {
+    /// Follows the lobby when it is renamed
+    pub lobby: LobbyHandle,
+    pub id: u32,
+    answer: oneshot::Receiver<UserMessage>,
+}
+
+pub struct ServerOptions {
+    /// Maximum size in bytes of a request body on `/api/client`
+    pub client_body_limit: u64,
+    /// Maximum size in bytes of a message on `/api/host`
+    pub host_message_limit: u64,
+    /// Maximum number of lobbies open at the same time
+    pub max_lobbies: Option<usize>,
+    /// Upper bound for the `max_clients` of every lobby
+    pub max_clients: u16,
+    /// Time a host has to send the create-lobby message
+    pub create_lobby_timeout: Duration,
+    /// Time the name of a closed lobby stays reserved for its host
+    pub reservation_period: Duration,
+    /// Time a host has to answer a join request
+    pub join_timeout: Duration,
+    /// Time a client WebSocket can stay silent, before its join request or
+    /// between ice candidates
+    pub client_idle_timeout: Duration,
+    /// Limits on the requests of each IP address
+    pub rate_limits: RateLimits,
+    /// Origins of the pages allowed to use the API, like `https://example.com`.
+    /// `None` allows any origin
+    pub allowed_origins: Option<Vec<String>>,
+    /// Methods allowed in the CORS requests to `/api/client`
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in the CORS requests to `/api/client`
+    pub allowed_headers: Vec<String>,
+    /// Time the pending joins have to be answered when the server shuts down
+    pub shutdown_grace_period: Duration,
+    /// Bearer token of the admin API. Without it the API is disabled
+    pub admin_token: Option<String>,
+    /// Time a lobby is kept after its host disconnects, so it can resume it
+    pub host_resume_period: Duration,
+    /// Maximum size in bytes of the keys and values of the metadata of a lobby
+    pub max_metadata_size: usize,
+    /// Rules for the names the hosts give to their lobbies
+    pub lobby_names: NameRules,
+    /// Maximum number of connections standing by to host a lobby
+    pub max_candidates: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            client_body_limit: 1024 * 2,
+            host_message_limit: 1024 * 16,
+            max_lobbies: None,
+            max_clients: u16::MAX,
+            create_lobby_timeout: Duration::from_secs(10),
+            reservation_period: Duration::from_secs(60),
+            join_timeout: Duration::from_secs(10),
+            client_idle_timeout: Duration::from_secs(30),
+            rate_limits: RateLimits::default(),
+            allowed_origins: None,
+            allowed_methods: vec!["POST".to_string()],
+            allowed_headers: vec!["content-type".to_string(), PROTOCOL_HEADER.to_string()],
+            shutdown_grace_period: Duration::from_secs(10),
+            admin_token: None,
+            host_resume_period: Duration::from_secs(10),
+            max_metadata_size: 1024,
+            lobby_names: NameRules::default(),
+            max_candidates: 8,
+        }
+    }
+}
 
-#[derive(Default)]
 pub struct Server {
-    lobbies_map: RwLock<LoobiesMap>,
-    public_lobbies: RwLock<HashSet<String>>,
+    options: ServerOptions,
+    database: RwLock<Box<dyn LobbyDatabase>>,
+    rate_limiter: RateLimiter,
+    shutting_down: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new(ServerOptions::default())
+    }
 }
 
 impl Server {
-    async fn create_lobby<'a>(
-        lobbies_map: &mut RwLockWriteGuard<'a, LoobiesMap>,
-        host_channel: HostChannel,
+    /// Server that keeps the lobbies in a [`RamDatabase`]
+    pub fn new(options: ServerOptions) -> Server {
+        let database = RamDatabase::new(options.reservation_period);
+        Server::with_database(options, database)
+    }
+
+    pub fn with_database(options: ServerOptions, database: impl LobbyDatabase + 'static) -> Server {
+        Server {
+            rate_limiter: RateLimiter::new(options.rate_limits),
+            options,
+            database: RwLock::new(Box::new(database)),
+            shutting_down: AtomicBool::new(false),
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    pub fn options(&self) -> &ServerOptions {
+        &self.options
+    }
+
+    /// Metrics in the Prometheus text format
+    pub async fn metrics(&self) -> String {
+        let database = self.database.read().await;
+        self.metrics
+            .encode(database.lobby_count(), database.public_lobbies().count())
+    }
+
+    pub(crate) fn message_received(&self, role: Role, message: &UserMessage) {
+        self.metrics.message_received(role, message);
+    }
+
+    /// Sends a message through a WebSocket. Errors are counted in the metrics
+    pub(crate) async fn send(
+        &self,
+        channel: &mut HostChannel,
+        protocol: &Protocol,
+        message: impl Into<UserMessage>,
+    ) -> Result<(), ()> {
+        let message = message.into();
+        self.metrics.message_sent(&message);
+        channel
+            .send(protocol.encode(&message))
+            .await
+            .map_err(|_| ())
+    }
+
+    /// Answer to a request to `/api/client`. Errors are counted in the metrics
+    pub(crate) fn reply(&self, protocol: &Protocol, message: &UserMessage) -> reply::Json {
+        self.metrics.message_sent(message);
+        protocol.reply(message)
+    }
+
+    /// Whether the token gives access to the admin API
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        match &self.options.admin_token {
+            // Hashes are compared to not leak the token through the comparison time
+            Some(admin_token) => SecretHash::new(admin_token).matches(token),
+            None => false,
+        }
+    }
+
+    /// Once shutting down, no lobby can be created or joined
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting lobbies and joins, tells every host that the server is
+    /// stopping, and waits until the pending joins are answered or the grace period ends
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+
+        let grace_period = self.options.shutdown_grace_period;
+        tracing::info!(?grace_period, "Shutting down");
+
+        {
+            let database = self.database.read().await;
+            for lobby in database.lobbies() {
+                lobby.lock().await.send_shutdown_to_host(grace_period).await;
+            }
+        }
+
+        let deadline = Instant::now() + grace_period;
+        loop {
+            let pending_joins = self.pending_joins().await;
+            if pending_joins == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!(pending_joins, "Grace period ended with pending joins");
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    async fn pending_joins(&self) -> usize {
+        let database = self.database.read().await;
+        let mut pending_joins = 0;
+        for lobby in database.lobbies() {
+            pending_joins += lobby.lock().await.pending_joins();
+        }
+        pending_joins
+    }
+
+    /// Requests without an address, like the ones of embedding code, are not limited
+    pub fn check_rate_limit(
+        &self,
+        address: Option<IpAddr>,
+        action: RateLimitedAction,
+    ) -> Result<(), UserMessageError> {
+        let Some(address) = address else {
+            return Ok(());
+        };
+
+        if self.rate_limiter.allow(address, action) {
+            Ok(())
+        } else {
+            tracing::info!(%address, ?action, "Rate limited");
+            Err(UserMessageError::RateLimited)
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_lobby(
+        &self,
+        database: &mut dyn LobbyDatabase,
+        mut host_channel: HostChannel,
+        host_protocol: Protocol,
         lobby_name: String,
-    ) -> Result<String, ()> {
-        let lobby = Lobby::create(
+        reservation_key: Option<String>,
+        password: Option<String>,
+        host_address: Option<IpAddr>,
+        request_id: Option<String>,
+    ) -> Result<LobbyHandle, ()> {
+        if self.is_shutting_down() {
+            let error = UserMessageError::ServerShuttingDown.with_request_id(request_id);
+            let _ = self.send(&mut host_channel, &host_protocol, error).await;
+            return Err(());
+        }
+
+        if let Err(error) = self.check_rate_limit(host_address, RateLimitedAction::CreateLobby) {
+            let error = error.with_request_id(request_id);
+            let _ = self.send(&mut host_channel, &host_protocol, error).await;
+            return Err(());
+        }
+
+        if let Some(max_lobbies) = self.options.max_lobbies {
+            if database.lobby_count() >= max_lobbies {
+                tracing::info!(%lobby_name, "Can not create lobby because the server is full");
+                let error = UserMessageError::TooManyLobbies.with_request_id(request_id);
+                let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                return Err(());
+            }
+        }
+
+        if let Some(address) = host_address {
+            if !self.rate_limiter.open_lobby(address) {
+                tracing::info!(
+                    %lobby_name,
+                    %address,
+                    "Can not create lobby because the address has too many"
+                );
+                let error = UserMessageError::RateLimited.with_request_id(request_id);
+                let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                return Err(());
+            }
+        }
+
+        let mut lobby = Lobby::create(
             host_channel,
             LobbyDetails {
                 lobby_name: lobby_name.clone(),
                 public_lobby: false,
                 max_clients: 0,
                 client_count: 0,
+                has_password: false,
+                metadata: Metadata::new(),
             },
+            self.metrics.clone(),
         );
 
-        match lobbies_map.try_insert(lobby_name.clone(), Mutex::new(lobby)) {
-            Ok(_) => Ok(lobby_name),
-            Err(error) => {
-                log::user_action!("Can not create lobby because name already exists");
-                let mut lobby = error.value.lock().await;
-                lobby
-                    .send_error_to_host(UserMessageError::LobbyAlreadyExists)
-                    .await;
+        if let Some(reservation_key) = reservation_key {
+            lobby.set_reservation_key(&reservation_key);
+        }
+
+        if let Some(password) = password {
+            lobby.set_password(&password);
+        }
+
+        if let Some(address) = host_address {
+            lobby.set_host_address(address);
+        }
+
+        lobby.set_host_protocol(host_protocol);
+        let resume_token = random_secret();
+        lobby.set_resume_token(&resume_token);
+
+        let handle = lobby.handle();
+        match database.add_lobby(lobby) {
+            Ok(()) => {
+                tracing::info!(%lobby_name, "Created lobby");
+                let sent = match database.get_lobby(&lobby_name) {
+                    Some(lobby) => lobby.lock().await.send_resume_token(resume_token).await,
+                    None => Err(()),
+                };
+                if sent.is_err() {
+                    // The host is gone, nobody would ever close the lobby
+                    let _ = self.remove_lobby(database, &lobby_name);
+                    return Err(());
+                }
+                Ok(handle)
+            }
+            Err(mut lobby) => {
+                tracing::info!(%lobby_name, "Can not create lobby because name already exists");
+                if let Some(address) = host_address {
+                    self.rate_limiter.close_lobby(address);
+                }
+                let error = UserMessageError::LobbyAlreadyExists.with_request_id(request_id);
+                lobby.send_error_to_host(error).await;
                 Err(())
             }
         }
     }
 
-    fn new_random_lobby_name<'a>(lobbies_map: &mut RwLockWriteGuard<'a, LoobiesMap>) -> String {
-        let mut lobby_name = random_word().to_string();
+    /// Joins random words until the name is free. `None` if no name following the
+    /// rules was found, like when they forbid letters
+    fn new_random_lobby_name(&self, database: &dyn LobbyDatabase) -> Option<String> {
+        let rules = &self.options.lobby_names;
+        let separator = match rules.characters.contains(&CharacterClass::Spaces) {
+            true => " ",
+            false => "",
+        };
 
-        loop {
-            if !lobbies_map.contains_key(&lobby_name) {
-                return lobby_name;
+        for _ in 0..RANDOM_NAME_ATTEMPTS {
+            let mut lobby_name = random_word().to_string();
+            while database.contains_lobby(&lobby_name)
+                && lobby_name.chars().count() < rules.max_length
+            {
+                lobby_name += separator;
+                lobby_name += random_word();
             }
 
-            lobby_name += " ";
-            lobby_name += random_word();
+            if let Ok(lobby_name) = rules.check(&lobby_name) {
+                if !database.contains_lobby(&lobby_name) {
+                    return Some(lobby_name);
+                }
+            }
         }
+        None
     }
 
-    async fn create_default_lobby<'a>(
+    #[allow(clippy::too_many_arguments)]
+    async fn create_default_lobby(
         &self,
-        host_channel: HostChannel,
+        mut host_channel: HostChannel,
+        host_protocol: Protocol,
         lobby_name: Option<String>,
-    ) -> Result<String, ()> {
-        let mut lobbies_map = self.lobbies_map.write().await;
+        reservation_key: Option<String>,
+        password: Option<String>,
+        host_address: Option<IpAddr>,
+        request_id: Option<String>,
+    ) -> Result<LobbyHandle, ()> {
+        let mut database = self.database.write().await;
         let lobby_name = match lobby_name {
             Some(lobby_name) => lobby_name,
-            None => Self::new_random_lobby_name(&mut lobbies_map),
+            None => match self.new_random_lobby_name(&**database) {
+                Some(lobby_name) => lobby_name,
+                None => {
+                    tracing::warn!("Could not pick a random lobby name following the rules");
+                    let error = UserMessageError::InvalidField
+                        .with_field("lobbyName", "The lobby needs a name")
+                        .with_request_id(request_id);
+                    let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                    return Err(());
+                }
+            },
         };
-        Self::create_lobby(&mut lobbies_map, host_channel, lobby_name).await
+        self.create_lobby(
+            &mut **database,
+            host_channel,
+            host_protocol,
+            lobby_name,
+            reservation_key,
+            password,
+            host_address,
+            request_id,
+        )
+        .await
     }
 
     async fn get_lobby<'a>(
-        lobbies_map: &'a RwLockReadGuard<'a, LoobiesMap>,
-        lobby_name: &String,
+        database: &'a dyn LobbyDatabase,
+        lobby_name: &str,
     ) -> Result<MutexGuard<'a, Lobby>, ()> {
-        let Some(lobby) = lobbies_map.get(lobby_name) else {
+        let Some(lobby) = database.get_lobby(lobby_name) else {
             return Err(());
         };
 
         Ok(lobby.lock().await)
     }
 
+    /// Lobby the handle follows. Fails once that lobby is closed, even if another
+    /// lobby has been opened with its name since
+    async fn get_lobby_of<'a>(
+        database: &'a dyn LobbyDatabase,
+        handle: &LobbyHandle,
+    ) -> Result<MutexGuard<'a, Lobby>, ()> {
+        if !handle.is_open() {
+            return Err(());
+        }
+        Self::get_lobby(database, &handle.name()).await
+    }
+
+    /// Lobby of the connection that sent a message, while it hosts it. Fails if the
+    /// lobby has been closed or given to another connection since, and then the
+    /// connection has to be dropped
+    async fn get_hosted_lobby<'a>(
+        database: &'a dyn LobbyDatabase,
+        handle: &LobbyHandle,
+    ) -> Result<MutexGuard<'a, Lobby>, ()> {
+        match Self::get_lobby_of(database, handle).await {
+            Ok(lobby) if lobby.is_hosted_by(handle) => Ok(lobby),
+            _ => {
+                tracing::info!(lobby_name = %handle.name(), "The connection no longer hosts the lobby");
+                Err(())
+            }
+        }
+    }
+
+    /// Tells the host that the server failed to handle its message, before it is
+    /// disconnected. Nothing is sent if the lobby can not be found either
+    async fn send_internal_error(
+        database: &dyn LobbyDatabase,
+        lobby_name: &str,
+        request_id: Option<String>,
+    ) {
+        if let Ok(mut lobby) = Self::get_lobby(database, lobby_name).await {
+            let error = UserMessageError::Internal.with_request_id(request_id);
+            lobby.send_error_to_host(error).await;
+        }
+    }
+
     pub async fn create_lobby_from_message(
         &self,
         message: UserMessage,
         mut host_channel: HostChannel,
-    ) -> Result<String, ()> {
+        host_protocol: Protocol,
+        host_address: Option<IpAddr>,
+    ) -> Result<LobbyHandle, ()> {
         match message {
             UserMessage::CreateLobby {
                 lobby_name,
                 public_lobby,
                 max_clients,
+                reservation_key,
+                password,
+                metadata,
+                request_id,
             } => {
-                log::user_action!("Received create-lobby message");
-                let lobby_name = self.create_default_lobby(host_channel, lobby_name).await?;
-
-                self.update_lobby(
-                    &lobby_name,
-                    LobbyDetails {
-                        lobby_name: lobby_name.clone(),
-                        public_lobby,
-                        max_clients,
-                        client_count: 0,
+                let lobby_name = match lobby_name {
+                    Some(lobby_name) => match self.options.lobby_names.check(&lobby_name) {
+                        Ok(lobby_name) => Some(lobby_name),
+                        Err(reason) => {
+                            tracing::info!(%reason, "Can not create lobby because of its name");
+                            let error = UserMessageError::InvalidField
+                                .with_field("lobbyName", reason)
+                                .with_request_id(request_id);
+                            let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                            return Err(());
+                        }
                     },
-                )
-                .await
+                    None => None,
+                };
+
+                if metadata_size(&metadata) > self.options.max_metadata_size {
+                    tracing::info!("Can not create lobby because its metadata is too large");
+                    let error = UserMessageError::MetadataTooLarge
+                        .with_field("metadata", "The metadata of the lobby is too large")
+                        .with_request_id(request_id);
+                    let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                    return Err(());
+                }
+
+                let has_password = password.is_some();
+                let handle = self
+                    .create_default_lobby(
+                        host_channel,
+                        host_protocol,
+                        lobby_name,
+                        reservation_key,
+                        password,
+                        host_address,
+                        request_id.clone(),
+                    )
+                    .await?;
+
+                let lobby_name = handle.name();
+                let updated = self
+                    .update_lobby(
+                        &lobby_name,
+                        LobbyDetails {
+                            lobby_name: lobby_name.clone(),
+                            public_lobby,
+                            max_clients,
+                            client_count: 0,
+                            has_password,
+                            metadata,
+                        },
+                        request_id,
+                    )
+                    .await;
+                if updated.is_err() {
+                    let mut database = self.database.write().await;
+                    let _ = self.remove_lobby(&mut **database, &lobby_name);
+                    return Err(());
+                }
+                Ok(handle)
             }
-            _ => {
-                let error_msg = UserMessageError::InvalidMessage.into();
-                let _ = host_channel.send(error_msg).await;
+            message => {
+                let error = UserMessageError::InvalidMessage
+                    .with_message(format!(
+                        "Expected a create-lobby message, not {}",
+                        message.type_name()
+                    ))
+                    .with_request_id(message.request_id().map(str::to_string));
+                let _ = self.send(&mut host_channel, &host_protocol, error).await;
                 Err(())
             }
         }
     }
 
-    pub async fn close_lobby(&self, lobby_name: &String) {
-        log::user_action!("Closeing lobby '{lobby_name}'");
-        // Delete from lobbies map
-        let lobby = {
-            let mut lobbies_map = self.lobbies_map.write().await;
-            let Some(lobby) = lobbies_map.remove(lobby_name) else {
+    /// Removes the lobby and closes the WebSocket of its host
+    pub async fn close_lobby(&self, lobby_name: &str) -> Result<(), ()> {
+        let lobby_name = self.options.lobby_names.normalize(lobby_name);
+        let mut database = self.database.write().await;
+        let lobby = self.remove_lobby(&mut **database, &lobby_name)?;
+        drop(database);
+
+        for mut channel in lobby.into_host_channels() {
+            let _ = channel.close().await;
+        }
+        Ok(())
+    }
+
+    /// Every open lobby, public or not, with what only the server knows about it
+    pub async fn lobbies_info(&self) -> Vec<LobbyInfo> {
+        let database = self.database.read().await;
+
+        let mut lobbies = vec![];
+        for lobby in database.lobbies() {
+            let lobby = lobby.lock().await;
+            lobbies.push(LobbyInfo {
+                details: lobby.details().clone(),
+                pending_joins: lobby.pending_joins(),
+                host_address: lobby.host_address(),
+            });
+        }
+        lobbies
+    }
+
+    /// Renames a lobby or changes whether it is public, as its host would, with the
+    /// same rules for the name. Returns the new details of the lobby, or the error
+    /// with the reason a name is refused
+    pub async fn edit_lobby(
+        &self,
+        lobby_name: &str,
+        new_name: Option<String>,
+        public_lobby: Option<bool>,
+    ) -> Result<LobbyDetails, UserMessage> {
+        let lobby_name = &self.options.lobby_names.normalize(lobby_name);
+        let new_name = match new_name {
+            Some(new_name) => match self.options.lobby_names.check(&new_name) {
+                Ok(new_name) => Some(new_name),
+                Err(reason) => {
+                    return Err(UserMessageError::InvalidField.with_field("lobbyName", reason))
+                }
+            },
+            None => None,
+        };
+
+        let mut details = {
+            let database = self.database.read().await;
+            let Ok(lobby) = Self::get_lobby(&**database, lobby_name).await else {
+                return Err(UserMessageError::LobbyNotFound.into());
+            };
+
+            if let Some(new_name) = &new_name {
+                let renamed = lobby_key(new_name) != lobby_key(lobby_name);
+                if renamed && database.contains_lobby(new_name) {
+                    return Err(UserMessageError::LobbyAlreadyExists.into());
+                }
+            }
+            lobby.details().clone()
+        };
+
+        if let Some(new_name) = new_name {
+            details.lobby_name = new_name;
+        }
+        if let Some(public_lobby) = public_lobby {
+            details.public_lobby = public_lobby;
+        }
+
+        let requested_name = details.lobby_name.clone();
+        let Ok(lobby_name) = self.update_lobby(lobby_name, details, None).await else {
+            return Err(UserMessageError::LobbyNotFound.into());
+        };
+        // The name has been taken since it was checked
+        if lobby_name != requested_name {
+            return Err(UserMessageError::LobbyAlreadyExists.into());
+        }
+
+        let database = self.database.read().await;
+        let Ok(lobby) = Self::get_lobby(&**database, &lobby_name).await else {
+            return Err(UserMessageError::LobbyNotFound.into());
+        };
+        Ok(lobby.details().clone())
+    }
+
+    /// Gives a lobby to a new connection of its host, if the token matches
+    pub async fn resume_lobby_from_message(
+        &self,
+        message: UserMessage,
+        mut host_channel: HostChannel,
+        host_protocol: Protocol,
+        host_address: Option<IpAddr>,
+    ) -> Result<LobbyHandle, ()> {
+        let UserMessage::ResumeLobby {
+            lobby_name,
+            resume_token,
+            request_id,
+        } = message
+        else {
+            let request_id = message.request_id().map(str::to_string);
+            let error = UserMessageError::InvalidMessage.with_request_id(request_id);
+            let _ = self.send(&mut host_channel, &host_protocol, error).await;
+            return Err(());
+        };
+        let lobby_name = self.options.lobby_names.normalize(&lobby_name);
+
+        if self.is_shutting_down() {
+            let error = UserMessageError::ServerShuttingDown.with_request_id(request_id);
+            let _ = self.send(&mut host_channel, &host_protocol, error).await;
+            return Err(());
+        }
+
+        let database = self.database.read().await;
+        let mut lobby = match Self::get_lobby(&**database, &lobby_name).await {
+            Ok(lobby) if lobby.accepts_resume_token(&resume_token) => lobby,
+            _ => {
+                tracing::info!(%lobby_name, "Can not resume lobby");
+                let error = UserMessageError::LobbyNotFound.with_request_id(request_id);
+                let _ = self.send(&mut host_channel, &host_protocol, error).await;
+                return Err(());
+            }
+        };
+
+        // The lobby counts for the address it is hosted from
+        let previous_address = lobby.host_address();
+        let resumed = lobby
+            .resume(host_channel, host_protocol, host_address, request_id)
+            .await;
+        self.rate_limiter
+            .move_lobby(previous_address, lobby.host_address());
+        let handle = lobby.handle();
+        drop(lobby);
+        drop(database);
+
+        let Ok(mut previous) = resumed else {
+            // The new connection is gone too, nobody else would close the lobby
+            self.close_host_lobby(&handle).await;
+            return Err(());
+        };
+        tracing::info!(%lobby_name, "Host resumed lobby");
+
+        // The previous connection may not have noticed that it dropped
+        let _ = previous.close().await;
+        Ok(handle)
+    }
+
+    /// Adds a connection that stands by to host a lobby when its host leaves
+    pub async fn standby_from_message(
+        &self,
+        message: UserMessage,
+        mut channel: HostChannel,
+        protocol: Protocol,
+        address: Option<IpAddr>,
+    ) -> Result<LobbyHandle, ()> {
+        let UserMessage::StandbyHost {
+            lobby_name,
+            password,
+            request_id,
+        } = message
+        else {
+            let request_id = message.request_id().map(str::to_string);
+            let error = UserMessageError::InvalidMessage.with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        };
+        let lobby_name = self.options.lobby_names.normalize(&lobby_name);
+
+        if !protocol.has(Feature::Migration) {
+            let error = UserMessageError::InvalidMessage
+                .with_message("The migration feature was not agreed on in the hello")
+                .with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        }
+
+        if self.is_shutting_down() {
+            let error = UserMessageError::ServerShuttingDown.with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        }
+
+        if let Err(error) = self.check_rate_limit(address, RateLimitedAction::StandbyHost) {
+            let error = error.with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        }
+
+        let database = self.database.read().await;
+        let Ok(mut lobby) = Self::get_lobby(&**database, &lobby_name).await else {
+            let error = UserMessageError::LobbyNotFound.with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        };
+
+        if !lobby.accepts_password(password.as_deref()) {
+            tracing::info!(%lobby_name, "Wrong password for lobby");
+            let error = UserMessageError::WrongPassword.with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        }
+
+        if lobby.candidate_count() >= self.options.max_candidates {
+            tracing::info!(%lobby_name, "Lobby has too many candidates");
+            let error = UserMessageError::LobbyFull
+                .with_message("The lobby has too many candidates")
+                .with_request_id(request_id);
+            let _ = self.send(&mut channel, &protocol, error).await;
+            return Err(());
+        }
+
+        let handle = lobby
+            .add_candidate(channel, protocol, address, request_id)
+            .await?;
+        tracing::info!(%lobby_name, "Candidate stands by to host lobby");
+        Ok(handle)
+    }
+
+    async fn is_candidate(&self, handle: &LobbyHandle) -> bool {
+        let database = self.database.read().await;
+        let Ok(lobby) = Self::get_lobby_of(&**database, handle).await else {
+            return false;
+        };
+        lobby.is_candidate(handle)
+    }
+
+    async fn is_hosted_by(&self, handle: &LobbyHandle) -> bool {
+        let database = self.database.read().await;
+        let Ok(lobby) = Self::get_lobby_of(&**database, handle).await else {
+            return false;
+        };
+        lobby.is_hosted_by(handle)
+    }
+
+    /// Called when a connection of a host or a candidate drops. The lobby is kept during
+    /// the resume period if the host has a resume token. After it, unless the host came
+    /// back or the lobby is already closed, a candidate becomes the host, or the lobby is
+    /// closed if there is none
+    pub async fn close_host_lobby(&self, handle: &LobbyHandle) {
+        let can_resume = {
+            let database = self.database.read().await;
+            let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await else {
                 return;
             };
-            lobby
+            if !lobby.disconnect_host(handle) {
+                lobby.remove_candidate(handle);
+                return;
+            }
+            lobby.host_protocol().has(Feature::Resume)
         };
 
-        // Delete from public lobbies list
-        let lobby = lobby.lock().await;
+        let resume_period = self.options.host_resume_period;
+        if can_resume && !resume_period.is_zero() {
+            tracing::info!(lobby_name = %handle.name(), "Host disconnected, waiting for it to resume");
+            time::sleep(resume_period).await;
+        }
 
-        if lobby.is_public() {
-            let mut public_lobbies = self.public_lobbies.write().await;
-            public_lobbies.remove(lobby_name);
+        // Sending the lobby to a candidate can take long, so only the lobby is locked
+        loop {
+            {
+                let database = self.database.read().await;
+                let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await else {
+                    return;
+                };
+                if !lobby.is_abandoned_by(handle) {
+                    return;
+                }
+
+                let previous_address = lobby.host_address();
+                if lobby.migrate_host().await.is_ok() {
+                    tracing::info!(lobby_name = %handle.name(), "Lobby migrated to a new host");
+                    self.rate_limiter
+                        .move_lobby(previous_address, lobby.host_address());
+                    return;
+                }
+            }
+
+            let mut database = self.database.write().await;
+            let Ok(lobby) = Self::get_lobby_of(&**database, handle).await else {
+                return;
+            };
+            if !lobby.is_abandoned_by(handle) {
+                return;
+            }
+            // A candidate stood by while the others were tried
+            if lobby.candidate_count() > 0 {
+                continue;
+            }
+            drop(lobby);
+            let _ = self.remove_lobby(&mut **database, &handle.name());
+            return;
         }
     }
 
+    fn remove_lobby(
+        &self,
+        database: &mut dyn LobbyDatabase,
+        lobby_name: &str,
+    ) -> Result<Lobby, ()> {
+        tracing::info!(%lobby_name, "Closing lobby");
+        let lobby = database.remove_lobby(lobby_name)?;
+
+        if let Some(address) = lobby.host_address() {
+            self.rate_limiter.close_lobby(address);
+        }
+        Ok(lobby)
+    }
+
     // Returns the new lobby name
     async fn update_lobby(
         &self,
-        lobby_name: &String,
+        lobby_name: &str,
         mut new_details: LobbyDetails,
+        request_id: Option<String>,
     ) -> Result<String, ()> {
-        log::user_action!("Updateing lobby '{lobby_name}'");
+        new_details.max_clients = new_details.max_clients.min(self.options.max_clients);
+        new_details.client_count = new_details.client_count.min(new_details.max_clients);
+
+        let mut database = self.database.write().await;
 
         // Try Rename Lobby
-        let lobby_name = match lobby_name == &new_details.lobby_name {
-            true => lobby_name.clone(),
+        let lobby_name = match lobby_name == new_details.lobby_name {
+            true => lobby_name.to_string(),
             false => {
-                let mut lobbies_map = self.lobbies_map.write().await;
-
-                if lobbies_map.contains_key(&new_details.lobby_name) {
+                let renamed = lobby_key(lobby_name) != lobby_key(&new_details.lobby_name);
+                if renamed && database.contains_lobby(&new_details.lobby_name) {
                     // Ignore lobby rename
-                    new_details.lobby_name = lobby_name.clone();
-                    lobby_name.clone()
+                    new_details.lobby_name = lobby_name.to_string();
+                    lobby_name.to_string()
                 } else {
                     // Rename lobby
-                    let Some(lobby) = lobbies_map.remove(lobby_name) else {
-                        log::error!("The lobby of a host is not registered");
+                    if database
+                        .rename_lobby(lobby_name, &new_details.lobby_name)
+                        .is_err()
+                    {
+                        tracing::error!(%lobby_name, "The lobby of a host is not registered");
+                        Self::send_internal_error(&**database, lobby_name, request_id).await;
                         return Err(());
-                    };
-
-                    lobbies_map.insert(new_details.lobby_name.clone(), lobby);
+                    }
+                    if let Some(lobby) = database.get_lobby(&new_details.lobby_name) {
+                        lobby.lock().await.notify_rename(&new_details.lobby_name);
+                    }
                     new_details.lobby_name.clone()
                 }
             }
         };
 
-        let lobbies_map = self.lobbies_map.read().await;
-        let Ok(mut lobby) = Self::get_lobby(&lobbies_map, &lobby_name).await else {
-            log::error!("The lobby of a host is not registered");
+        // Update public lobbies list
+        if database.update_details(&lobby_name, &new_details).is_err() {
+            tracing::error!(%lobby_name, "The lobby of a host is not registered");
+            Self::send_internal_error(&**database, &lobby_name, request_id).await;
+            return Err(());
+        }
+
+        let database = database.downgrade();
+        let Ok(mut lobby) = Self::get_lobby(&**database, &lobby_name).await else {
+            tracing::error!(%lobby_name, "The lobby of a host is not registered");
             return Err(());
         };
 
-        // Update public lobbies list
-        if lobby.is_public() != new_details.public_lobby {
-            let mut public_lobbies = self.public_lobbies.write().await;
-            if new_details.public_lobby {
-                public_lobbies.insert(lobby_name.clone());
-            } else {
-                public_lobbies.remove(&lobby_name);
-            }
+        tracing::info!(%lobby_name, details = ?new_details, "Updating lobby");
+        lobby.update_details(new_details, request_id).await?;
+        Ok(lobby_name)
+    }
+
+    /// Normalizes the name of the details sent by a host and checks them.
+    /// Fails with the error to send back
+    fn check_details(&self, details: &mut LobbyDetails) -> Result<(), UserMessage> {
+        details.lobby_name = self
+            .options
+            .lobby_names
+            .check(&details.lobby_name)
+            .map_err(|reason| {
+                UserMessageError::InvalidField.with_field("details.lobbyName", reason)
+            })?;
+
+        if details.client_count > details.max_clients {
+            let error = UserMessageError::InvalidField.with_field(
+                "details.clientCount",
+                "The client count can not be above the max clients",
+            );
+            return Err(error);
         }
 
-        lobby.update_details(new_details).await?;
-        Ok(lobby_name)
+        if details.metadata_size() > self.options.max_metadata_size {
+            let error = UserMessageError::MetadataTooLarge
+                .with_field("details.metadata", "The metadata of the lobby is too large");
+            return Err(error);
+        }
+
+        Ok(())
     }
 
-    // Return the lobby name (a message could have change it)
+    /// Answers a message of the host that could not be read.
+    /// Candidates are not answered until they host the lobby
+    pub async fn send_error_to_host(&self, handle: &LobbyHandle, error: impl Into<UserMessage>) {
+        let database = self.database.read().await;
+        let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await else {
+            return;
+        };
+        if lobby.is_hosted_by(handle) {
+            lobby.send_error_to_host(error).await;
+        }
+    }
+
+    /// Only fails if the host has to be disconnected
     pub async fn handle_host_message(
         &self,
-        lobby_name: &String,
+        handle: &LobbyHandle,
         message: UserMessage,
-    ) -> Result<String, ()> {
+    ) -> Result<(), ()> {
+        // The lobby may have been resumed from another connection
+        if !self.is_hosted_by(handle).await {
+            if self.is_candidate(handle).await {
+                tracing::warn!("Candidate host should wait to become the host");
+                return Ok(());
+            }
+            return Err(());
+        }
+
         match message {
-            UserMessage::LobbyDetails { details } => self.update_lobby(lobby_name, details).await,
-            UserMessage::JoinInvitation { answer, id } => {
-                let lobbies_map = self.lobbies_map.read().await;
-                let Ok(mut lobby) = Self::get_lobby(&lobbies_map, lobby_name).await else {
-                    log::error!("The lobby of a host is not registered");
+            UserMessage::LobbyDetails {
+                mut details,
+                request_id,
+            } => {
+                if let Err(error) = self.check_details(&mut details) {
+                    let database = self.database.read().await;
+                    let lobby_name = &handle.name();
+                    tracing::info!(%lobby_name, ?error, "The host sent invalid details");
+                    if let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await {
+                        lobby
+                            .send_error_to_host(error.with_request_id(request_id))
+                            .await;
+                    }
+                    return Ok(());
+                }
+
+                self.update_lobby(&handle.name(), details, request_id)
+                    .await?;
+                Ok(())
+            }
+            UserMessage::JoinInvitation {
+                answer,
+                id,
+                request_id,
+            } => {
+                let database = self.database.read().await;
+                let lobby_name = &handle.name();
+                let Ok(mut lobby) = Self::get_hosted_lobby(&**database, handle).await else {
+                    return Err(());
+                };
+
+                let Some(id) = id else {
+                    tracing::warn!(%lobby_name, "The join invitation should have an id");
+                    let error = UserMessageError::InvalidField
+                        .with_field("id", "The join invitation should have an id")
+                        .with_request_id(request_id);
+                    lobby.send_error_to_host(error).await;
+                    return Err(());
+                };
+
+                tracing::info!(%lobby_name, id, "Host accepted join request");
+                lobby.send_invitation(answer, id, request_id).await
+            }
+            UserMessage::JoinRejection {
+                id,
+                reason,
+                code,
+                request_id,
+            } => {
+                let database = self.database.read().await;
+                let lobby_name = &handle.name();
+                let Ok(mut lobby) = Self::get_hosted_lobby(&**database, handle).await else {
                     return Err(());
                 };
 
                 let Some(id) = id else {
-                    log::user_error!("The join invitation should have an id");
-                    lobby.send_error_to_host(UserMessageError::InvalidMessage).await;
+                    tracing::warn!(%lobby_name, "The join rejection should have an id");
+                    let error = UserMessageError::InvalidField
+                        .with_field("id", "The join rejection should have an id")
+                        .with_request_id(request_id);
+                    lobby.send_error_to_host(error).await;
+                    return Err(());
+                };
+
+                tracing::info!(%lobby_name, id, %reason, "Host rejected join request");
+                lobby.send_rejection(id, reason, code, request_id).await
+            }
+            UserMessage::IceCandidate {
+                candidate,
+                id,
+                request_id,
+            } => {
+                let database = self.database.read().await;
+                let lobby_name = &handle.name();
+                let Ok(mut lobby) = Self::get_hosted_lobby(&**database, handle).await else {
+                    return Err(());
+                };
+
+                let Some(id) = id else {
+                    tracing::warn!(%lobby_name, "The ice candidate should have an id");
+                    let error = UserMessageError::InvalidField
+                        .with_field("id", "The ice candidate should have an id")
+                        .with_request_id(request_id);
+                    lobby.send_error_to_host(error).await;
+                    return Err(());
+                };
+
+                lobby
+                    .send_ice_candidate_to_client(id, candidate, request_id)
+                    .await;
+                Ok(())
+            }
+            UserMessage::DesignateSuccessor {
+                candidate_id,
+                request_id,
+            } => {
+                let database = self.database.read().await;
+                let lobby_name = &handle.name();
+                let Ok(mut lobby) = Self::get_hosted_lobby(&**database, handle).await else {
+                    return Err(());
+                };
+
+                if !lobby.host_protocol().has(Feature::Migration) {
+                    let error = UserMessageError::InvalidMessage
+                        .with_message("The migration feature was not agreed on in the hello")
+                        .with_request_id(request_id);
+                    lobby.send_error_to_host(error).await;
+                    return Ok(());
+                }
+
+                if lobby.designate_successor(candidate_id).is_err() {
+                    tracing::warn!(%lobby_name, candidate_id, "The successor is not standing by");
+                    let error = UserMessageError::InvalidField
+                        .with_field("candidateId", "The successor is not standing by")
+                        .with_request_id(request_id);
+                    lobby.send_error_to_host(error).await;
+                    return Ok(());
+                }
+
+                tracing::info!(%lobby_name, candidate_id, "Host designated its successor");
+                lobby.acknowledge(request_id).await
+            }
+            message => {
+                let database = self.database.read().await;
+                let lobby_name = &handle.name();
+                let Ok(mut lobby) = Self::get_hosted_lobby(&**database, handle).await else {
                     return Err(());
                 };
 
-                lobby.send_invitation(answer, id)?;
-                Ok(lobby_name.clone())
+                let message_type = message.type_name();
+                tracing::warn!(%lobby_name, message_type, "Hosts should not send this message");
+                let error = UserMessageError::InvalidMessage
+                    .with_message(format!("Hosts should not send {message_type} messages"))
+                    .with_request_id(message.request_id().map(str::to_string));
+                lobby.send_error_to_host(error).await;
+                Ok(())
             }
-            _ => Err(()),
         }
     }
 
-    pub async fn handle_user_message(&self, message: UserMessage) -> UserMessage {
+    /// Sends the join request to the host of the lobby, or of any public lobby
+    /// with free places that accepts the password if there is no name.
+    /// With an ice channel, the ice candidates of the host are relayed to the client.
+    pub async fn request_join(
+        &self,
+        lobby_name: Option<String>,
+        offer: String,
+        password: Option<String>,
+        ice_channel: Option<ClientChannel>,
+    ) -> Result<PendingJoin, UserMessageError> {
+        if self.is_shutting_down() {
+            return Err(UserMessageError::ServerShuttingDown);
+        }
+
+        let database = self.database.read().await;
+
+        let mut lobby = if let Some(lobby_name) = lobby_name {
+            let lobby_name = self.options.lobby_names.normalize(&lobby_name);
+            let Ok(lobby) = Self::get_lobby(&**database, &lobby_name).await else {
+                return Err(UserMessageError::LobbyNotFound);
+            };
+
+            if !lobby.accepts_password(password.as_deref()) {
+                tracing::info!(%lobby_name, "Wrong password for lobby");
+                return Err(UserMessageError::WrongPassword);
+            }
+            lobby
+        } else {
+            // Pick any lobby with free places from the public list
+            let mut free_lobby = None;
+            for lobby in database.public_lobbies() {
+                let lobby = lobby.lock().await;
+                if lobby.capacity() > 0 && lobby.accepts_password(password.as_deref()) {
+                    free_lobby = Some(lobby);
+                    break;
+                }
+            }
+
+            let Some(lobby) = free_lobby else {
+                return Err(UserMessageError::LobbyNotFound);
+            };
+            lobby
+        };
+
+        let (id, answer) = lobby.request_invitation(offer, ice_channel).await?;
+
+        Ok(PendingJoin {
+            lobby: lobby.handle(),
+            id,
+            answer,
+        })
+    }
+
+    /// Returns the answer of the host, or an error if it does not arrive in time
+    pub async fn wait_join_answer(&self, pending_join: PendingJoin) -> UserMessage {
+        let PendingJoin {
+            lobby: handle,
+            id,
+            answer,
+        } = pending_join;
+
+        match time::timeout(self.options.join_timeout, answer).await {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(_)) => UserMessageError::LobbyNotFound.into(),
+            Err(_) => {
+                // The request no longer counts against the places of the lobby,
+                // even if it has been renamed
+                let database = self.database.read().await;
+                tracing::info!(lobby_name = %handle.name(), id, "The host did not answer in time");
+                if let Ok(mut lobby) = Self::get_lobby_of(&**database, &handle).await {
+                    lobby.expire_join_request(id).await;
+                }
+                UserMessageError::JoinTimeout.into()
+            }
+        }
+    }
+
+    /// Relays an ice candidate of a client to the host of the lobby it is joining
+    pub async fn send_ice_candidate_to_host(
+        &self,
+        handle: &LobbyHandle,
+        id: u32,
+        candidate: String,
+    ) -> Result<(), UserMessageError> {
+        let database = self.database.read().await;
+        let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await else {
+            return Err(UserMessageError::LobbyNotFound);
+        };
+
+        lobby.send_ice_candidate_to_host(id, candidate).await
+    }
+
+    /// Stops relaying the ice candidates of the host to a client
+    pub async fn close_ice_channel(&self, handle: &LobbyHandle, id: u32) {
+        let database = self.database.read().await;
+        if let Ok(mut lobby) = Self::get_lobby_of(&**database, handle).await {
+            lobby.close_ice_channel(id);
+        };
+    }
+
+    pub async fn handle_user_message(
+        &self,
+        message: UserMessage,
+        client_address: Option<IpAddr>,
+    ) -> UserMessage {
+        let action = match message {
+            UserMessage::JoinRequest { .. } => RateLimitedAction::JoinRequest,
+            UserMessage::LobbiesListRequest { .. } => RateLimitedAction::LobbiesList,
+            message => {
+                return UserMessageError::InvalidMessage.with_message(format!(
+                    "Clients should not send {} messages",
+                    message.type_name()
+                ))
+            }
+        };
+
+        if let Err(error) = self.check_rate_limit(client_address, action) {
+            return error.into();
+        }
+
         match message {
             UserMessage::JoinRequest {
                 lobby_name,
                 offer,
                 id: _,
+                password,
             } => {
-                log::user_action!("Received join-request");
-
-                let join_invitation = {
-                    let lobby_name = if let Some(lobby_name) = lobby_name {
-                        lobby_name
-                    } else {
-                        // Pick any lobby from the public list
-                        let public_lobbies = self.public_lobbies.read().await;
-                        match public_lobbies.iter().next() {
-                            Some(lobby_name) => lobby_name.clone(),
-                            None => return UserMessageError::LobbyNotFound.into(),
-                        }
-                    };
-                    log::user_action!("Joining to lobby '{lobby_name}'");
-
-                    let lobbies_map = self.lobbies_map.read().await;
-                    let Ok(mut lobby) = Self::get_lobby(&lobbies_map, &lobby_name).await else {
-                        return UserMessageError::LobbyNotFound.into();
-                    };
-
-                    lobby.request_invitation(offer).await
-                };
+                tracing::info!(lobby_name = lobby_name.as_deref(), "Received join-request");
 
-                let Ok(join_invitation) = join_invitation else {
-                    return UserMessageError::LobbyNotFound.into();
-                };
-
-                if let Ok(join_invitation) = join_invitation.await {
-                    join_invitation
-                } else {
-                    UserMessageError::LobbyNotFound.into()
+                match self.request_join(lobby_name, offer, password, None).await {
+                    Ok(pending_join) => self.wait_join_answer(pending_join).await,
+                    Err(error) => error.into(),
                 }
             }
             UserMessage::LobbiesListRequest {
                 maximum_lobbies,
                 minimum_capacity,
+                filters,
+                sort,
+                descending,
+                cursor,
             } => {
-                log::user_action!("Received lobbies-list-request");
+                tracing::info!(
+                    maximum_lobbies,
+                    minimum_capacity,
+                    ?filters,
+                    ?sort,
+                    descending,
+                    cursor = cursor.as_deref(),
+                    "Received lobbies-list-request"
+                );
 
-                let lobbies_map = self.lobbies_map.read().await;
-                let public_lobbies = self.public_lobbies.read().await;
+                let database = self.database.read().await;
 
                 let mut lobbies = vec![];
 
-                for lobby_name in public_lobbies.iter() {
-                    let Some(lobby) = lobbies_map.get(lobby_name) else {
-                        continue;
-                    };
-
+                for lobby in database.public_lobbies() {
                     let lobby = lobby.lock().await;
-                    let details = lobby.details();
 
-                    if details.capacity() < minimum_capacity {
+                    if lobby.capacity() < minimum_capacity {
                         continue;
                     }
 
-                    lobbies.push(details.clone());
-                    if lobbies.len() >= maximum_lobbies {
-                        break;
+                    let metadata = &lobby.details().metadata;
+                    if !filters.iter().all(|filter| filter.matches(metadata)) {
+                        continue;
                     }
+
+                    lobbies.push(ListedLobby::new(&lobby, sort));
                 }
 
-                UserMessage::LobbiesList { lobbies }
+                let Ok(page) = listing::page(
+                    lobbies,
+                    sort,
+                    descending,
+                    cursor.as_deref(),
+                    maximum_lobbies,
+                ) else {
+                    tracing::info!("The cursor of the lobbies list is invalid");
+                    return UserMessageError::InvalidField
+                        .with_field("cursor", "The cursor is not one of this sort");
+                };
+
+                UserMessage::LobbiesList {
+                    lobbies: page.lobbies,
+                    total: page.total,
+                    next_cursor: page.next_cursor,
+                }
             }
             _ => UserMessageError::InvalidMessage.into(),
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::api::routes;
+    use serde_json::{json, Value};
+    use tokio::task::JoinHandle;
+    use warp::test::WsClient;
+
+    /// Host WebSocket that agreed on the latest protocol with every feature
+    pub(crate) async fn connect_host(server: &Arc<Server>) -> WsClient {
+        let mut host = warp::test::ws()
+            .path("/api/host")
+            .handshake(routes(server.clone()))
+            .await
+            .unwrap();
+        let features = ["resume", "migration", "shutdown"];
+        send(
+            &mut host,
+            json!({ "type": "hello", "version": 2, "features": features }),
+        )
+        .await;
+        assert_eq!(receive(&mut host).await["type"], "hello");
+        host
+    }
+
+    pub(crate) async fn send(ws: &mut WsClient, message: Value) {
+        ws.send_text(message.to_string()).await;
+    }
+
+    pub(crate) async fn receive(ws: &mut WsClient) -> Value {
+        let message = time::timeout(Duration::from_secs(5), ws.recv())
+            .await
+            .expect("no message in time")
+            .unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    /// Host of a new public lobby, with its resume token
+    pub(crate) async fn create_lobby(server: &Arc<Server>, lobby_name: &str) -> (WsClient, String) {
+        let mut host = connect_host(server).await;
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": lobby_name,
+            "publicLobby": true,
+            "maxClients": 4,
+        });
+        send(&mut host, create).await;
+        let resume_token = receive(&mut host).await["resumeToken"].clone();
+        assert_eq!(receive(&mut host).await["type"], "lobby-details");
+        (host, resume_token.as_str().unwrap().to_string())
+    }
+
+    /// Host of a new public lobby with the password `secret`
+    async fn create_locked_lobby(server: &Arc<Server>, lobby_name: &str) -> (WsClient, Value) {
+        let mut host = connect_host(server).await;
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": lobby_name,
+            "publicLobby": true,
+            "maxClients": 4,
+            "password": "secret",
+        });
+        send(&mut host, create).await;
+        assert_eq!(receive(&mut host).await["type"], "resume-token");
+        let details = receive(&mut host).await;
+        assert_eq!(details["type"], "lobby-details");
+        (host, details["details"].clone())
+    }
+
+    async fn connect_client(server: &Arc<Server>) -> WsClient {
+        warp::test::ws()
+            .path("/api/client")
+            .handshake(routes(server.clone()))
+            .await
+            .unwrap()
+    }
+
+    /// Posts a request to `/api/client`. Join requests wait for the answer of the host
+    fn post(server: &Arc<Server>, request: Value) -> JoinHandle<Value> {
+        let routes = routes(server.clone());
+        tokio::spawn(async move {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/api/client")
+                .json(&request)
+                .reply(&routes)
+                .await;
+            serde_json::from_slice(response.body()).unwrap()
+        })
+    }
+
+    /// Posts a join request, that waits for the answer of the host
+    fn join(server: &Arc<Server>, lobby_name: &str) -> JoinHandle<Value> {
+        let request = json!({ "type": "join-request", "lobbyName": lobby_name, "offer": "o" });
+        post(server, request)
+    }
+
+    #[tokio::test]
+    async fn lobbies_are_found_by_their_normalized_name() {
+        let server = Arc::new(Server::default());
+        let (mut host, _) = create_lobby(&server, "Cafe\u{301}").await;
+
+        for lobby_name in ["Café", "Cafe\u{301}", " Café ", "CAFE\u{301}"] {
+            let joined = join(&server, lobby_name);
+            let request = receive(&mut host).await;
+            assert_eq!(request["type"], "join-request", "{lobby_name:?}");
+            let answer = json!({ "type": "join-invitation", "answer": "a", "id": request["id"] });
+            send(&mut host, answer).await;
+            assert_eq!(joined.await.unwrap()["answer"], "a");
+            assert_eq!(receive(&mut host).await["type"], "lobby-details");
+        }
+
+        let edited = server.edit_lobby(" cafe\u{301}", None, Some(false)).await;
+        assert!(!edited.unwrap().public_lobby);
+        assert!(server.close_lobby("Cafe\u{301} ").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn host_messages_without_reply_are_acknowledged() {
+        let server = Arc::new(Server::default());
+        let (mut host, _) = create_lobby(&server, "Acks").await;
+
+        let joined = join(&server, "Acks");
+        let id = receive(&mut host).await["id"].clone();
+        let rejection =
+            json!({ "type": "join-rejection", "reason": "no", "id": id, "requestId": "1" });
+        send(&mut host, rejection).await;
+        assert_eq!(
+            receive(&mut host).await,
+            json!({ "type": "ack", "requestId": "1" })
+        );
+        assert_eq!(joined.await.unwrap()["type"], "join-rejection");
+
+        let mut client = connect_client(&server).await;
+        let request = json!({ "type": "join-request", "lobbyName": "Acks", "offer": "o" });
+        send(&mut client, request).await;
+        let id = receive(&mut host).await["id"].clone();
+        let candidate =
+            json!({ "type": "ice-candidate", "candidate": "c", "id": id, "requestId": "2" });
+        send(&mut host, candidate).await;
+        assert_eq!(
+            receive(&mut host).await,
+            json!({ "type": "ack", "requestId": "2" })
+        );
+        assert_eq!(receive(&mut client).await["candidate"], "c");
+
+        // Without a request id, nothing answers the candidate
+        send(
+            &mut host,
+            json!({ "type": "ice-candidate", "candidate": "d", "id": id }),
+        )
+        .await;
+        assert_eq!(receive(&mut client).await["candidate"], "d");
+        let invitation =
+            json!({ "type": "join-invitation", "answer": "a", "id": id, "requestId": "3" });
+        send(&mut host, invitation).await;
+        let details = receive(&mut host).await;
+        assert_eq!(
+            (&details["type"], &details["requestId"]),
+            (&json!("lobby-details"), &json!("3"))
+        );
+    }
+
+    #[tokio::test]
+    async fn late_answers_are_refused_with_their_request_id() {
+        let server = Arc::new(Server::new(ServerOptions {
+            join_timeout: Duration::from_millis(100),
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Late").await;
+
+        let joined = join(&server, "Late");
+        let id = receive(&mut host).await["id"].clone();
+        assert_eq!(joined.await.unwrap()["errorType"], "joinTimeout");
+        assert_eq!(
+            receive(&mut host).await,
+            json!({ "type": "join-expired", "id": id })
+        );
+
+        let invitation =
+            json!({ "type": "join-invitation", "answer": "a", "id": id, "requestId": "4" });
+        send(&mut host, invitation).await;
+        let error = receive(&mut host).await;
+        assert_eq!(
+            (&error["field"], &error["requestId"]),
+            (&json!("id"), &json!("4"))
+        );
+    }
+
+    #[tokio::test]
+    async fn client_websockets_follow_renamed_lobbies() {
+        let server = Arc::new(Server::default());
+        let (mut host, _) = create_lobby(&server, "Before").await;
+
+        let mut client = connect_client(&server).await;
+        let request = json!({ "type": "join-request", "lobbyName": "Before", "offer": "o" });
+        send(&mut client, request).await;
+        let id = receive(&mut host).await["id"].clone();
+
+        let details =
+            json!({ "lobbyName": "After", "publicLobby": true, "maxClients": 4, "clientCount": 0 });
+        send(
+            &mut host,
+            json!({ "type": "lobby-details", "details": details }),
+        )
+        .await;
+        assert_eq!(receive(&mut host).await["details"]["lobbyName"], "After");
+
+        send(
+            &mut client,
+            json!({ "type": "ice-candidate", "candidate": "c" }),
+        )
+        .await;
+        let candidate = receive(&mut host).await;
+        assert_eq!(
+            (&candidate["candidate"], &candidate["id"]),
+            (&json!("c"), &id)
+        );
+
+        // Closing the client WebSocket releases its ice channel in the renamed lobby
+        drop(client);
+        let candidate =
+            json!({ "type": "ice-candidate", "candidate": "c", "id": id, "requestId": "1" });
+        let mut error = json!(null);
+        for _ in 0..50 {
+            send(&mut host, candidate.clone()).await;
+            error = receive(&mut host).await;
+            if error["type"] == "error" {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            (&error["field"], &error["requestId"]),
+            (&json!("id"), &json!("1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_client_websockets_are_closed() {
+        let server = Arc::new(Server::new(ServerOptions {
+            client_idle_timeout: Duration::from_millis(100),
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Quiet").await;
+
+        let mut silent = connect_client(&server).await;
+        let closed = time::timeout(Duration::from_secs(5), silent.recv_closed()).await;
+        assert!(
+            closed.is_ok(),
+            "the WebSocket without join-request stays open"
+        );
+
+        let mut client = connect_client(&server).await;
+        let request = json!({ "type": "join-request", "lobbyName": "Quiet", "offer": "o" });
+        send(&mut client, request).await;
+        let id = receive(&mut host).await["id"].clone();
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": id }),
+        )
+        .await;
+        assert_eq!(receive(&mut client).await["answer"], "a");
+        let closed = time::timeout(Duration::from_secs(5), client.recv_closed()).await;
+        assert!(
+            closed.is_ok(),
+            "the silent WebSocket stays open after joining"
+        );
+    }
+
+    #[tokio::test]
+    async fn timed_out_joins_free_their_place_in_renamed_lobbies() {
+        let server = Arc::new(Server::new(ServerOptions {
+            join_timeout: Duration::from_millis(100),
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Before").await;
+
+        let joined = join(&server, "Before");
+        let id = receive(&mut host).await["id"].clone();
+        let details =
+            json!({ "lobbyName": "After", "publicLobby": true, "maxClients": 1, "clientCount": 0 });
+        send(
+            &mut host,
+            json!({ "type": "lobby-details", "details": details }),
+        )
+        .await;
+        assert_eq!(receive(&mut host).await["details"]["lobbyName"], "After");
+
+        assert_eq!(joined.await.unwrap()["errorType"], "joinTimeout");
+        assert_eq!(
+            receive(&mut host).await,
+            json!({ "type": "join-expired", "id": id })
+        );
+        assert_eq!(server.lobbies_info().await[0].pending_joins, 0);
+
+        let joined = join(&server, "After");
+        let request = receive(&mut host).await;
+        assert_eq!(request["type"], "join-request");
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+    }
+
+    #[tokio::test]
+    async fn connections_of_a_closed_lobby_leave_its_successor_alone() {
+        let server = Arc::new(Server::new(ServerOptions {
+            host_resume_period: Duration::ZERO,
+            ..ServerOptions::default()
+        }));
+        let (old_host, _) = create_lobby(&server, "Reused").await;
+        assert!(server.close_lobby("Reused").await.is_ok());
+        let (mut host, _) = create_lobby(&server, "Reused").await;
+
+        drop(old_host);
+        time::sleep(Duration::from_millis(100)).await;
+
+        let joined = join(&server, "Reused");
+        let request = receive(&mut host).await;
+        assert_eq!(request["type"], "join-request");
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+    }
+
+    /// Waits for the server to close every lobby, like after their hosts left
+    async fn wait_lobbies_closed(server: &Server) {
+        for _ in 0..100 {
+            if server.lobbies_info().await.is_empty() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the lobbies stay open");
+    }
+
+    /// Host WebSocket that sent a resume-lobby message
+    async fn resume_lobby(server: &Arc<Server>, lobby_name: &str, resume_token: &str) -> WsClient {
+        let mut host = connect_host(server).await;
+        let resume = json!({
+            "type": "resume-lobby",
+            "lobbyName": lobby_name,
+            "resumeToken": resume_token,
+            "requestId": "resume",
+        });
+        send(&mut host, resume).await;
+        host
+    }
+
+    #[tokio::test]
+    async fn hosts_resume_their_lobby_with_the_pending_joins() {
+        let server = Arc::new(Server::default());
+        let (mut old_host, resume_token) = create_lobby(&server, "Resumed").await;
+        let joined = join(&server, "Resumed");
+        let id = receive(&mut old_host).await["id"].clone();
+
+        let mut host = resume_lobby(&server, "Resumed", "wrong token").await;
+        let error = receive(&mut host).await;
+        assert_eq!(
+            (&error["errorType"], &error["requestId"]),
+            (&json!("lobbyNotFound"), &json!("resume"))
+        );
+
+        let mut host = resume_lobby(&server, "Resumed", &resume_token).await;
+        let details = receive(&mut host).await;
+        assert_eq!(
+            (&details["type"], &details["requestId"]),
+            (&json!("lobby-details"), &json!("resume"))
+        );
+        let request = receive(&mut host).await;
+        assert_eq!(
+            (&request["type"], &request["id"]),
+            (&json!("join-request"), &id)
+        );
+
+        let closed = time::timeout(Duration::from_secs(5), old_host.recv_closed()).await;
+        assert!(closed.is_ok(), "the previous connection stays open");
+
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": id }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+        let info = &server.lobbies_info().await[0];
+        assert_eq!(info.host_address, Some(IpAddr::from([127, 0, 0, 1])));
+    }
+
+    #[tokio::test]
+    async fn lobbies_close_when_their_host_does_not_resume_in_time() {
+        let server = Arc::new(Server::new(ServerOptions {
+            host_resume_period: Duration::from_millis(300),
+            ..ServerOptions::default()
+        }));
+        let (host, resume_token) = create_lobby(&server, "Left").await;
+
+        drop(host);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.lobbies_info().await.len(), 1);
+
+        wait_lobbies_closed(&server).await;
+
+        let mut host = resume_lobby(&server, "Left", &resume_token).await;
+        assert_eq!(receive(&mut host).await["errorType"], "lobbyNotFound");
+    }
+
+    /// Candidate of the lobby, with its id
+    async fn standby(server: &Arc<Server>, lobby_name: &str) -> (WsClient, Value) {
+        let mut candidate = connect_host(server).await;
+        send(
+            &mut candidate,
+            json!({ "type": "standby-host", "lobbyName": lobby_name }),
+        )
+        .await;
+        let accepted = receive(&mut candidate).await;
+        assert_eq!(accepted["type"], "standby-accepted");
+        (candidate, accepted["candidateId"].clone())
+    }
+
+    #[tokio::test]
+    async fn designated_successors_take_the_lobby_when_its_host_leaves() {
+        let server = Arc::new(Server::new(ServerOptions {
+            host_resume_period: Duration::ZERO,
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Migrated").await;
+        let (_first, _) = standby(&server, "Migrated").await;
+        let (mut successor, successor_id) = standby(&server, "Migrated").await;
+
+        let designate =
+            json!({ "type": "designate-successor", "candidateId": 999, "requestId": "1" });
+        send(&mut host, designate).await;
+        let error = receive(&mut host).await;
+        assert_eq!(
+            (&error["field"], &error["requestId"]),
+            (&json!("candidateId"), &json!("1"))
+        );
+        let designate =
+            json!({ "type": "designate-successor", "candidateId": successor_id, "requestId": "2" });
+        send(&mut host, designate).await;
+        assert_eq!(
+            receive(&mut host).await,
+            json!({ "type": "ack", "requestId": "2" })
+        );
+
+        let joined = join(&server, "Migrated");
+        let id = receive(&mut host).await["id"].clone();
+        drop(host);
+
+        assert_eq!(receive(&mut successor).await["type"], "resume-token");
+        assert_eq!(receive(&mut successor).await["type"], "lobby-details");
+        let request = receive(&mut successor).await;
+        assert_eq!(
+            (&request["type"], &request["id"]),
+            (&json!("join-request"), &id)
+        );
+        send(
+            &mut successor,
+            json!({ "type": "join-invitation", "answer": "a", "id": id }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+    }
+
+    #[tokio::test]
+    async fn candidates_that_can_not_be_reached_are_skipped() {
+        let server = Arc::new(Server::new(ServerOptions {
+            host_resume_period: Duration::from_millis(100),
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Migrated").await;
+        let (gone, gone_id) = standby(&server, "Migrated").await;
+        let (mut candidate, _) = standby(&server, "Migrated").await;
+        let designate =
+            json!({ "type": "designate-successor", "candidateId": gone_id, "requestId": "1" });
+        send(&mut host, designate).await;
+        assert_eq!(receive(&mut host).await["type"], "ack");
+
+        drop(host);
+        time::sleep(Duration::from_millis(50)).await;
+
+        // The lobby migrates before the server forgets the designated successor
+        // that left, as if it left while the lobby migrated
+        let database = server.database.read().await;
+        let lobby = Server::get_lobby(&**database, "Migrated").await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        drop(gone);
+        time::sleep(Duration::from_millis(50)).await;
+        drop(lobby);
+        drop(database);
+
+        assert_eq!(receive(&mut candidate).await["type"], "resume-token");
+        let details = receive(&mut candidate).await;
+        assert_eq!(details["details"]["lobbyName"], "Migrated");
+        assert_eq!(server.lobbies_info().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn candidates_need_the_migration_feature_and_the_password() {
+        let server = Arc::new(Server::default());
+        let (_host, _) = create_locked_lobby(&server, "Locked").await;
+
+        let mut candidate = connect_host(&server).await;
+        let standby = json!({ "type": "standby-host", "lobbyName": "Locked", "password": "wrong" });
+        send(&mut candidate, standby).await;
+        assert_eq!(receive(&mut candidate).await["errorType"], "wrongPassword");
+
+        let mut candidate = warp::test::ws()
+            .path("/api/host")
+            .handshake(routes(server.clone()))
+            .await
+            .unwrap();
+        send(&mut candidate, json!({ "type": "hello", "version": 2 })).await;
+        assert_eq!(receive(&mut candidate).await["features"], json!([]));
+        let standby =
+            json!({ "type": "standby-host", "lobbyName": "Locked", "password": "secret" });
+        send(&mut candidate, standby).await;
+        assert_eq!(receive(&mut candidate).await["errorType"], "invalidMessage");
+    }
+
+    #[tokio::test]
+    async fn hosts_without_hello_get_no_feature() {
+        let server = Arc::new(Server::new(ServerOptions {
+            shutdown_grace_period: Duration::ZERO,
+            ..ServerOptions::default()
+        }));
+        let mut host = warp::test::ws()
+            .path("/api/host")
+            .handshake(routes(server.clone()))
+            .await
+            .unwrap();
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": "Old",
+            "publicLobby": true,
+            "maxClients": 4,
+        });
+        send(&mut host, create).await;
+        // No resume-token before the details
+        assert_eq!(receive(&mut host).await["type"], "lobby-details");
+
+        server.shutdown().await;
+        let message = time::timeout(Duration::from_millis(100), host.recv()).await;
+        assert!(message.is_err());
+
+        // Without a resume token, the lobby is not kept for the resume period
+        drop(host);
+        wait_lobbies_closed(&server).await;
+    }
+
+    #[tokio::test]
+    async fn shutting_down_servers_refuse_lobbies_and_joins() {
+        let server = Arc::new(Server::new(ServerOptions {
+            shutdown_grace_period: Duration::from_secs(3),
+            ..ServerOptions::default()
+        }));
+        let (mut host, _) = create_lobby(&server, "Open").await;
+
+        let mut old_host = warp::test::ws()
+            .path("/api/host")
+            .handshake(routes(server.clone()))
+            .await
+            .unwrap();
+        send(&mut old_host, json!({ "type": "hello", "version": 2 })).await;
+        assert_eq!(receive(&mut old_host).await["type"], "hello");
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": "Old",
+            "publicLobby": true,
+            "maxClients": 4,
+        });
+        send(&mut old_host, create).await;
+        assert_eq!(receive(&mut old_host).await["type"], "lobby-details");
+
+        let readyz = warp::test::request()
+            .path("/readyz")
+            .reply(&routes(server.clone()))
+            .await;
+        assert_eq!(readyz.status(), warp::http::StatusCode::OK);
+
+        server.shutdown().await;
+
+        let shutdown = receive(&mut host).await;
+        assert_eq!(shutdown["type"], "server-shutdown");
+        assert_eq!(shutdown["gracePeriod"], 3);
+        // Hosts without the shutdown feature are not told
+        let message = time::timeout(Duration::from_millis(100), old_host.recv()).await;
+        assert!(message.is_err());
+
+        let readyz = warp::test::request()
+            .path("/readyz")
+            .reply(&routes(server.clone()))
+            .await;
+        assert_eq!(readyz.status(), warp::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(
+            join(&server, "Open").await.unwrap()["errorType"],
+            "serverShuttingDown"
+        );
+
+        let mut new_host = connect_host(&server).await;
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": "New",
+            "publicLobby": true,
+            "maxClients": 4,
+        });
+        send(&mut new_host, create).await;
+        assert_eq!(
+            receive(&mut new_host).await["errorType"],
+            "serverShuttingDown"
+        );
+    }
+
+    #[tokio::test]
+    async fn full_lobbies_refuse_joins() {
+        let server = Arc::new(Server::default());
+        let (mut host, _) = create_lobby(&server, "Small").await;
+        let details =
+            json!({ "lobbyName": "Small", "publicLobby": true, "maxClients": 1, "clientCount": 0 });
+        send(
+            &mut host,
+            json!({ "type": "lobby-details", "details": details }),
+        )
+        .await;
+        assert_eq!(receive(&mut host).await["details"]["maxClients"], 1);
+
+        // The pending join takes the only place
+        let joined = join(&server, "Small");
+        let request = receive(&mut host).await;
+        assert_eq!(request["type"], "join-request");
+        assert_eq!(
+            join(&server, "Small").await.unwrap()["errorType"],
+            "lobbyFull"
+        );
+
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+        assert_eq!(receive(&mut host).await["details"]["clientCount"], 1);
+        assert_eq!(
+            join(&server, "Small").await.unwrap()["errorType"],
+            "lobbyFull"
+        );
+
+        // Hosts can report clients that left, but not add clients
+        let details =
+            json!({ "lobbyName": "Small", "publicLobby": true, "maxClients": 3, "clientCount": 3 });
+        send(
+            &mut host,
+            json!({ "type": "lobby-details", "details": details }),
+        )
+        .await;
+        let details = receive(&mut host).await["details"].clone();
+        assert_eq!(details["maxClients"], 3);
+        assert_eq!(details["clientCount"], 1);
+
+        let details =
+            json!({ "lobbyName": "Small", "publicLobby": true, "maxClients": 1, "clientCount": 0 });
+        send(
+            &mut host,
+            json!({ "type": "lobby-details", "details": details }),
+        )
+        .await;
+        assert_eq!(receive(&mut host).await["details"]["clientCount"], 0);
+
+        let joined = join(&server, "Small");
+        let request = receive(&mut host).await;
+        assert_eq!(request["type"], "join-request");
+        send(
+            &mut host,
+            json!({ "type": "join-rejection", "reason": "no", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["type"], "join-rejection");
+    }
+
+    #[tokio::test]
+    async fn servers_only_count_their_own_metrics() {
+        let server = Arc::new(Server::default());
+        let other = Arc::new(Server::default());
+        let (_host, _) = create_lobby(&server, "Counted").await;
+
+        assert_eq!(
+            join(&server, "Missing").await.unwrap()["errorType"],
+            "lobbyNotFound"
+        );
+        // Errors of the admin API are not sent to hosts and clients
+        assert!(server
+            .edit_lobby("Missing", None, Some(true))
+            .await
+            .is_err());
+
+        let metrics = server.metrics().await;
+        assert!(metrics.contains("rtc_lobby_lobbies 1"), "{metrics}");
+        assert!(metrics.contains(r#"rtc_lobby_errors_total{error="lobbyNotFound"} 1"#));
+        let metrics = other.metrics().await;
+        assert!(metrics.contains("rtc_lobby_lobbies 0"), "{metrics}");
+        assert!(!metrics.contains("rtc_lobby_errors_total{"));
+        assert!(!metrics.contains("rtc_lobby_messages_received_total{"));
+    }
+
+    #[tokio::test]
+    async fn locked_lobbies_need_their_password() {
+        let server = Arc::new(Server::default());
+        let (mut host, details) = create_locked_lobby(&server, "Locked").await;
+        assert_eq!(details["hasPassword"], true);
+
+        let list = json!({
+            "type": "lobbies-list-request",
+            "maximumLobbies": 10,
+            "minimumCapacity": 0,
+        });
+        let lobbies = post(&server, list).await.unwrap()["lobbies"].clone();
+        assert_eq!(lobbies[0]["hasPassword"], true);
+
+        assert_eq!(
+            join(&server, "Locked").await.unwrap()["errorType"],
+            "wrongPassword"
+        );
+        let request = json!({
+            "type": "join-request",
+            "lobbyName": "Locked",
+            "offer": "o",
+            "password": "wrong",
+        });
+        assert_eq!(
+            post(&server, request).await.unwrap()["errorType"],
+            "wrongPassword"
+        );
+
+        let request = json!({
+            "type": "join-request",
+            "lobbyName": "Locked",
+            "offer": "o",
+            "password": "secret",
+        });
+        let joined = post(&server, request);
+        let request = receive(&mut host).await;
+        assert_eq!(request["type"], "join-request");
+        assert!(request["password"].is_null());
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+    }
+
+    #[tokio::test]
+    async fn joins_without_name_skip_the_locked_lobbies() {
+        let server = Arc::new(Server::default());
+        let (_locked, _) = create_locked_lobby(&server, "Locked").await;
+
+        let request = json!({ "type": "join-request", "offer": "o" });
+        assert_eq!(
+            post(&server, request.clone()).await.unwrap()["errorType"],
+            "lobbyNotFound"
+        );
+
+        let (mut host, _) = create_lobby(&server, "Open").await;
+        let joined = post(&server, request);
+        let request = receive(&mut host).await;
+        assert_eq!(request["lobbyName"], "Open");
+        send(
+            &mut host,
+            json!({ "type": "join-invitation", "answer": "a", "id": request["id"] }),
+        )
+        .await;
+        assert_eq!(joined.await.unwrap()["answer"], "a");
+    }
+
+    fn sled_server(path: &std::path::Path) -> Arc<Server> {
+        let options = ServerOptions {
+            host_resume_period: Duration::ZERO,
+            ..ServerOptions::default()
+        };
+        let database = SledDatabase::open(path, options.reservation_period).unwrap();
+        Arc::new(Server::with_database(options, database))
+    }
+
+    /// Each runtime stands for a run of the server. Dropping it drops the test servers
+    /// spawned for the WebSockets, and with them the database
+    #[test]
+    fn reservations_survive_a_restart_of_the_sled_database() {
+        let path = std::env::temp_dir().join(format!("rtc-lobby-test-{}", random_secret()));
+        let create = json!({
+            "type": "create-lobby",
+            "lobbyName": "Kept",
+            "publicLobby": true,
+            "maxClients": 4,
+            "reservationKey": "key",
+        });
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let server = sled_server(&path);
+            let mut host = connect_host(&server).await;
+            send(&mut host, create.clone()).await;
+            assert_eq!(receive(&mut host).await["type"], "resume-token");
+            assert_eq!(receive(&mut host).await["type"], "lobby-details");
+
+            drop(host);
+            wait_lobbies_closed(&server).await;
+        });
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let server = sled_server(&path);
+            let mut host = connect_host(&server).await;
+            let mut wrong_key = create.clone();
+            wrong_key["lobbyName"] = json!("KEPT");
+            wrong_key["reservationKey"] = json!("other");
+            send(&mut host, wrong_key).await;
+            assert_eq!(receive(&mut host).await["errorType"], "lobbyAlreadyExists");
+
+            let mut host = connect_host(&server).await;
+            send(&mut host, create).await;
+            assert_eq!(receive(&mut host).await["type"], "resume-token");
+            let details = receive(&mut host).await;
+            assert_eq!(details["details"]["lobbyName"], "Kept");
+        });
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
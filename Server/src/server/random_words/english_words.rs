@@ -1,4 +1,4 @@
-pub const WORDS: [&'static str; 1952] = [
+pub static WORDS: [&str; 1952] = [
     "ability",
     "able",
     "aboard",
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How often the addresses that stopped sending requests are forgotten
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Requests limited per IP address
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitedAction {
+    CreateLobby,
+    JoinRequest,
+    LobbiesList,
+    StandbyHost,
+}
+
+/// Allows `per_minute` requests every minute, and bursts of up to `per_minute` requests
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32) -> TokenBucket {
+        TokenBucket {
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let per_minute = per_minute as f64;
+
+        self.tokens = (self.tokens + elapsed * per_minute / 60.0).min(per_minute);
+        self.updated = now;
+    }
+
+    fn take(&mut self, per_minute: u32) -> bool {
+        self.refill(per_minute);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, per_minute: u32) -> bool {
+        self.refill(per_minute);
+        self.tokens >= per_minute as f64
+    }
+}
+
+struct AddressUsage {
+    create_lobby: TokenBucket,
+    join_request: TokenBucket,
+    lobbies_list: TokenBucket,
+    standby_host: TokenBucket,
+    open_lobbies: usize,
+}
+
+impl AddressUsage {
+    /// Without open lobbies and with full buckets, the address can be forgotten
+    fn is_unused(&mut self, limits: &RateLimits) -> bool {
+        self.open_lobbies == 0
+            && self.create_lobby.is_full(limits.create_lobby.unwrap_or(0))
+            && self.join_request.is_full(limits.join_request.unwrap_or(0))
+            && self.lobbies_list.is_full(limits.lobbies_list.unwrap_or(0))
+            && self.standby_host.is_full(limits.standby_host.unwrap_or(0))
+    }
+}
+
+/// Requests per minute and IP address. `None` means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub create_lobby: Option<u32>,
+    pub join_request: Option<u32>,
+    pub lobbies_list: Option<u32>,
+    pub standby_host: Option<u32>,
+    /// Lobbies open at the same time by hosts with the same IP address
+    pub max_lobbies_per_ip: Option<usize>,
+}
+
+impl RateLimits {
+    fn per_minute(&self, action: RateLimitedAction) -> Option<u32> {
+        match action {
+            RateLimitedAction::CreateLobby => self.create_lobby,
+            RateLimitedAction::JoinRequest => self.join_request,
+            RateLimitedAction::LobbiesList => self.lobbies_list,
+            RateLimitedAction::StandbyHost => self.standby_host,
+        }
+    }
+}
+
+struct Usages {
+    addresses: HashMap<IpAddr, AddressUsage>,
+    pruned: Instant,
+}
+
+pub(super) struct RateLimiter {
+    limits: RateLimits,
+    usages: Mutex<Usages>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            usages: Mutex::new(Usages {
+                addresses: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn usages(&self) -> MutexGuard<'_, Usages> {
+        // The usages are valid even if a thread panicked while holding the lock
+        self.usages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a token of the bucket of the action, if the address has any left
+    pub fn allow(&self, address: IpAddr, action: RateLimitedAction) -> bool {
+        let Some(per_minute) = self.limits.per_minute(action) else {
+            return true;
+        };
+
+        let mut usages = self.usages();
+        self.prune(&mut usages);
+
+        let usage = self.usage(&mut usages, address);
+        let bucket = match action {
+            RateLimitedAction::CreateLobby => &mut usage.create_lobby,
+            RateLimitedAction::JoinRequest => &mut usage.join_request,
+            RateLimitedAction::LobbiesList => &mut usage.lobbies_list,
+            RateLimitedAction::StandbyHost => &mut usage.standby_host,
+        };
+        bucket.take(per_minute)
+    }
+
+    /// Counts a new lobby of the address, unless it already has too many open
+    pub fn open_lobby(&self, address: IpAddr) -> bool {
+        let Some(max_lobbies) = self.limits.max_lobbies_per_ip else {
+            return true;
+        };
+
+        let mut usages = self.usages();
+        self.prune(&mut usages);
+
+        let usage = self.usage(&mut usages, address);
+        if usage.open_lobbies >= max_lobbies {
+            return false;
+        }
+
+        usage.open_lobbies += 1;
+        true
+    }
+
+    pub fn close_lobby(&self, address: IpAddr) {
+        if self.limits.max_lobbies_per_ip.is_none() {
+            return;
+        }
+
+        let mut usages = self.usages();
+        self.prune(&mut usages);
+
+        if let Some(usage) = usages.addresses.get_mut(&address) {
+            usage.open_lobbies = usage.open_lobbies.saturating_sub(1);
+            if usage.is_unused(&self.limits) {
+                usages.addresses.remove(&address);
+            }
+        }
+    }
+
+    /// Counts the lobby for its new host after a migration, or for the new address of
+    /// its host after it resumed the lobby. The new address is counted
+    /// even over its limit, the lobby is already open
+    pub fn move_lobby(&self, from: Option<IpAddr>, to: Option<IpAddr>) {
+        if self.limits.max_lobbies_per_ip.is_none() || from == to {
+            return;
+        }
+
+        if let Some(from) = from {
+            self.close_lobby(from);
+        }
+        if let Some(to) = to {
+            let mut usages = self.usages();
+            self.usage(&mut usages, to).open_lobbies += 1;
+        }
+    }
+
+    fn usage<'a>(&self, usages: &'a mut Usages, address: IpAddr) -> &'a mut AddressUsage {
+        let limits = &self.limits;
+        usages
+            .addresses
+            .entry(address)
+            .or_insert_with(|| AddressUsage {
+                create_lobby: TokenBucket::new(limits.create_lobby.unwrap_or(0)),
+                join_request: TokenBucket::new(limits.join_request.unwrap_or(0)),
+                lobbies_list: TokenBucket::new(limits.lobbies_list.unwrap_or(0)),
+                standby_host: TokenBucket::new(limits.standby_host.unwrap_or(0)),
+                open_lobbies: 0,
+            })
+    }
+
+    /// Forgets the addresses without open lobbies whose buckets are full again
+    fn prune(&self, usages: &mut Usages) {
+        if usages.pruned.elapsed() < PRUNE_PERIOD {
+            return;
+        }
+
+        usages
+            .addresses
+            .retain(|_, usage| !usage.is_unused(&self.limits));
+        usages.pruned = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_of_a_minute() {
+        let mut bucket = TokenBucket::new(3);
+        assert!(bucket.is_full(3));
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(bucket.take(3));
+        assert!(!bucket.take(3));
+        assert!(!bucket.is_full(3));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(60);
+        for _ in 0..60 {
+            assert!(bucket.take(60));
+        }
+        assert!(!bucket.take(60));
+
+        bucket.updated -= Duration::from_secs(2);
+        assert!(bucket.take(60));
+        assert!(bucket.take(60));
+        assert!(!bucket.take(60));
+    }
+
+    #[test]
+    fn bucket_does_not_refill_over_its_size() {
+        let mut bucket = TokenBucket::new(2);
+        bucket.updated -= Duration::from_secs(600);
+        assert!(bucket.take(2));
+        assert!(bucket.take(2));
+        assert!(!bucket.take(2));
+    }
+
+    #[test]
+    fn limiter_counts_the_lobbies_of_each_address() {
+        let limiter = RateLimiter::new(RateLimits {
+            max_lobbies_per_ip: Some(1),
+            ..RateLimits::default()
+        });
+        let first: IpAddr = [10, 0, 0, 1].into();
+        let second: IpAddr = [10, 0, 0, 2].into();
+
+        assert!(limiter.open_lobby(first));
+        assert!(!limiter.open_lobby(first));
+        assert!(limiter.open_lobby(second));
+
+        limiter.move_lobby(Some(first), Some(second));
+        assert!(limiter.open_lobby(first));
+        limiter.close_lobby(second);
+        assert!(!limiter.open_lobby(second));
+        limiter.close_lobby(second);
+        assert!(limiter.open_lobby(second));
+    }
+
+    #[test]
+    fn unlimited_actions_are_always_allowed() {
+        let limiter = RateLimiter::new(RateLimits {
+            create_lobby: Some(1),
+            ..RateLimits::default()
+        });
+        let address: IpAddr = [10, 0, 0, 1].into();
+
+        assert!(limiter.allow(address, RateLimitedAction::CreateLobby));
+        assert!(!limiter.allow(address, RateLimitedAction::CreateLobby));
+        for _ in 0..100 {
+            assert!(limiter.allow(address, RateLimitedAction::JoinRequest));
+        }
+    }
+
+    #[test]
+    fn addresses_are_forgotten_with_their_last_lobby() {
+        let limiter = RateLimiter::new(RateLimits {
+            max_lobbies_per_ip: Some(2),
+            ..RateLimits::default()
+        });
+        let address: IpAddr = [10, 0, 0, 1].into();
+
+        assert!(limiter.open_lobby(address));
+        assert!(limiter.open_lobby(address));
+        limiter.close_lobby(address);
+        assert_eq!(limiter.usages().addresses.len(), 1);
+        limiter.close_lobby(address);
+        assert!(limiter.usages().addresses.is_empty());
+    }
+
+    #[test]
+    fn opening_lobbies_prunes_the_idle_addresses() {
+        let limiter = RateLimiter::new(RateLimits {
+            create_lobby: Some(60),
+            max_lobbies_per_ip: Some(1),
+            ..RateLimits::default()
+        });
+        let idle: IpAddr = [10, 0, 0, 1].into();
+        let host: IpAddr = [10, 0, 0, 2].into();
+
+        assert!(limiter.allow(idle, RateLimitedAction::CreateLobby));
+        {
+            let mut usages = limiter.usages();
+            usages
+                .addresses
+                .get_mut(&idle)
+                .unwrap()
+                .create_lobby
+                .updated -= Duration::from_secs(2);
+            usages.pruned -= PRUNE_PERIOD;
+        }
+
+        assert!(limiter.open_lobby(host));
+        let usages = limiter.usages();
+        assert!(!usages.addresses.contains_key(&idle));
+        assert!(usages.addresses.contains_key(&host));
+    }
+}
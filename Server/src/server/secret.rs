@@ -0,0 +1,72 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hash of a secret sent by a user, so it is never kept in plain text
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SecretHash([u8; 32]);
+
+/// Random secret of 32 alphanumeric characters, given by the server to a user
+pub fn random_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl SecretHash {
+    pub fn new(secret: &str) -> SecretHash {
+        SecretHash(Sha256::digest(secret.as_bytes()).into())
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        *self == SecretHash::new(secret)
+    }
+}
+
+/// Hash of a secret chosen by a user, like a password, with a random salt.
+/// The same password gives a different hash in each lobby
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaltedHash {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SaltedHash {
+    pub fn new(secret: &str) -> SaltedHash {
+        let salt = rand::thread_rng().gen();
+        SaltedHash {
+            salt,
+            hash: Self::digest(&salt, secret),
+        }
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        self.hash == Self::digest(&self.salt, secret)
+    }
+
+    fn digest(salt: &[u8; 16], secret: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(secret.as_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salted_hashes_of_the_same_secret_differ() {
+        let first = SaltedHash::new("hunter2");
+        let second = SaltedHash::new("hunter2");
+        assert_ne!(first, second);
+        assert!(first.matches("hunter2") && second.matches("hunter2"));
+        assert!(!first.matches("hunter3"));
+        assert!(!first.matches(""));
+    }
+}
@@ -0,0 +1,340 @@
+use serde::Deserialize;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+use super::database::lobby_key;
+
+/// Unicode normalization of the lobby names, so that names that look the same are equal
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Names are kept as sent
+    None,
+    /// Composed characters, like `é` sent as `e` and an accent, are merged
+    Nfc,
+    /// Like nfc, and compatibility characters, like `ﬁ` or full width letters,
+    /// are replaced by their usual form
+    Nfkc,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(normalization: &str) -> Result<Self, Self::Err> {
+        match normalization {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            _ => Err("expected one of: none, nfc, nfkc".to_string()),
+        }
+    }
+}
+
+/// Characters allowed in lobby names. Control characters, line breaks and invisible
+/// characters never are
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CharacterClass {
+    /// Letters of any script
+    Letters,
+    /// Digits and other numbers of any script
+    Digits,
+    /// The ASCII space, between words
+    Spaces,
+    /// ASCII punctuation, like `-`, `_`, `.` or `!`
+    Punctuation,
+    /// Any other visible character, like emojis
+    Symbols,
+}
+
+impl CharacterClass {
+    pub const ALL: [CharacterClass; 5] = [
+        CharacterClass::Letters,
+        CharacterClass::Digits,
+        CharacterClass::Spaces,
+        CharacterClass::Punctuation,
+        CharacterClass::Symbols,
+    ];
+
+    fn of(character: char) -> Option<CharacterClass> {
+        if character.is_control() || is_invisible(character) {
+            None
+        } else if character.is_alphabetic() {
+            Some(CharacterClass::Letters)
+        } else if character.is_numeric() {
+            Some(CharacterClass::Digits)
+        } else if character == ' ' {
+            Some(CharacterClass::Spaces)
+        } else if character.is_whitespace() {
+            None
+        } else if character.is_ascii_punctuation() {
+            Some(CharacterClass::Punctuation)
+        } else {
+            Some(CharacterClass::Symbols)
+        }
+    }
+}
+
+/// Format characters (general category Cf) and default ignorable code points, as of
+/// Unicode 15. They are not drawn, or change how the characters around them are, so
+/// names with them could look like other names
+const INVISIBLE: [(char, char); 25] = [
+    ('\u{ad}', '\u{ad}'),
+    ('\u{34f}', '\u{34f}'),
+    ('\u{600}', '\u{605}'),
+    ('\u{61c}', '\u{61c}'),
+    ('\u{6dd}', '\u{6dd}'),
+    ('\u{70f}', '\u{70f}'),
+    ('\u{890}', '\u{891}'),
+    ('\u{8e2}', '\u{8e2}'),
+    ('\u{115f}', '\u{1160}'),
+    ('\u{17b4}', '\u{17b5}'),
+    ('\u{180b}', '\u{180f}'),
+    ('\u{200b}', '\u{200f}'),
+    ('\u{202a}', '\u{202e}'),
+    ('\u{2060}', '\u{206f}'),
+    ('\u{3164}', '\u{3164}'),
+    ('\u{fe00}', '\u{fe0f}'),
+    ('\u{feff}', '\u{feff}'),
+    ('\u{ffa0}', '\u{ffa0}'),
+    ('\u{fff0}', '\u{fffb}'),
+    ('\u{110bd}', '\u{110bd}'),
+    ('\u{110cd}', '\u{110cd}'),
+    ('\u{13430}', '\u{1343f}'),
+    ('\u{1bca0}', '\u{1bca3}'),
+    ('\u{1d173}', '\u{1d17a}'),
+    ('\u{e0000}', '\u{e0fff}'),
+];
+
+fn is_invisible(character: char) -> bool {
+    INVISIBLE
+        .binary_search_by(|&(first, last)| {
+            if last < character {
+                std::cmp::Ordering::Less
+            } else if first > character {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+impl FromStr for CharacterClass {
+    type Err = String;
+
+    fn from_str(class: &str) -> Result<Self, Self::Err> {
+        match class {
+            "letters" => Ok(CharacterClass::Letters),
+            "digits" => Ok(CharacterClass::Digits),
+            "spaces" => Ok(CharacterClass::Spaces),
+            "punctuation" => Ok(CharacterClass::Punctuation),
+            "symbols" => Ok(CharacterClass::Symbols),
+            _ => Err("expected one of: letters, digits, spaces, punctuation, symbols".to_string()),
+        }
+    }
+}
+
+/// What the names of the lobbies chosen by the hosts must look like
+#[derive(Debug, Clone)]
+pub struct NameRules {
+    /// Length in characters, after the normalization
+    pub min_length: usize,
+    pub max_length: usize,
+    pub characters: Vec<CharacterClass>,
+    pub normalization: Normalization,
+    /// Names no host can take, whatever their case
+    pub reserved: Vec<String>,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules {
+            min_length: 1,
+            max_length: 64,
+            characters: CharacterClass::ALL.to_vec(),
+            normalization: Normalization::Nfc,
+            reserved: vec![],
+        }
+    }
+}
+
+impl NameRules {
+    /// Normalizes the name and trims its spaces. Fails with the reason
+    /// if the name breaks the rules
+    pub fn check(&self, lobby_name: &str) -> Result<String, String> {
+        let lobby_name = self.normalize(lobby_name);
+
+        let length = lobby_name.chars().count();
+        if length < self.min_length {
+            return Err(format!(
+                "The lobby name must have at least {} characters",
+                self.min_length
+            ));
+        }
+        if length > self.max_length {
+            return Err(format!(
+                "The lobby name must have at most {} characters",
+                self.max_length
+            ));
+        }
+
+        for character in lobby_name.chars() {
+            let allowed =
+                CharacterClass::of(character).is_some_and(|class| self.characters.contains(&class));
+            if !allowed {
+                return Err(format!(
+                    "The lobby name can not contain '{}'",
+                    character.escape_debug()
+                ));
+            }
+        }
+
+        let key = lobby_key(&lobby_name);
+        if self
+            .reserved
+            .iter()
+            .any(|reserved| lobby_key(&self.normalize(reserved)) == key)
+        {
+            return Err("The lobby name is reserved".to_string());
+        }
+
+        Ok(lobby_name)
+    }
+
+    /// Normalizes the name and trims its spaces like `check`, without checking it.
+    /// Names sent to find a lobby go through it, to match the checked names
+    pub fn normalize(&self, lobby_name: &str) -> String {
+        let lobby_name: String = match self.normalization {
+            Normalization::None => lobby_name.to_string(),
+            Normalization::Nfc => lobby_name.nfc().collect(),
+            Normalization::Nfkc => lobby_name.nfkc().collect(),
+        };
+        lobby_name.trim().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_normalized_and_trimmed() {
+        let rules = NameRules::default();
+        assert_eq!(rules.check("  Cafe\u{301} ").unwrap(), "Café");
+
+        let rules = NameRules {
+            normalization: Normalization::Nfkc,
+            ..NameRules::default()
+        };
+        assert_eq!(rules.check("ﬁle１").unwrap(), "file1");
+
+        let rules = NameRules {
+            normalization: Normalization::None,
+            ..NameRules::default()
+        };
+        assert_eq!(rules.check("Cafe\u{301}").unwrap(), "Cafe\u{301}");
+    }
+
+    #[test]
+    fn length_is_counted_in_characters() {
+        let rules = NameRules {
+            min_length: 2,
+            max_length: 4,
+            ..NameRules::default()
+        };
+        assert!(rules.check("é").is_err());
+        assert!(rules.check("  a  ").is_err());
+        assert!(rules.check("éé").is_ok());
+        assert!(rules.check("éééé").is_ok());
+        assert!(rules.check("ééééé").is_err());
+        assert!(NameRules::default().check("   ").is_err());
+    }
+
+    #[test]
+    fn only_allowed_characters_pass() {
+        let rules = NameRules {
+            characters: vec![CharacterClass::Letters, CharacterClass::Digits],
+            ..NameRules::default()
+        };
+        assert!(rules.check("Potatoes42").is_ok());
+        assert!(rules.check("Ρόδι٣").is_ok());
+        assert!(rules.check("two words").is_err());
+        assert!(rules.check("dash-ed").is_err());
+        assert!(rules.check("fire🔥").is_err());
+    }
+
+    #[test]
+    fn control_characters_never_pass() {
+        let rules = NameRules::default();
+        for name in ["a\nb", "a\tb", "a\u{0}b", "a\u{2028}b", "a\u{a0}b"] {
+            assert!(rules.check(name).is_err(), "{name:?} should be refused");
+        }
+        assert!(rules.check("a b-c!🔥").is_ok());
+    }
+
+    #[test]
+    fn invisible_characters_never_pass() {
+        assert!(INVISIBLE.windows(2).all(|ranges| ranges[0].1 < ranges[1].0));
+
+        let rules = NameRules::default();
+        let invisible = [
+            '\u{ad}',
+            '\u{61c}',
+            '\u{180e}',
+            '\u{200b}',
+            '\u{200d}',
+            '\u{200e}',
+            '\u{202e}',
+            '\u{2060}',
+            '\u{2066}',
+            '\u{3164}',
+            '\u{fe0f}',
+            '\u{feff}',
+            '\u{1d173}',
+            '\u{e0001}',
+            '\u{e0100}',
+        ];
+        for character in invisible {
+            let name = format!("Lob{character}by");
+            assert!(rules.check(&name).is_err(), "{name:?} should be refused");
+        }
+        assert!(rules.check("\u{feff}Lobby").is_err());
+        assert!(rules.check("Lobby\u{200b}").is_err());
+        assert!(rules.check("Lo\u{2010}bby\u{1f525}").is_ok());
+    }
+
+    #[test]
+    fn invisible_characters_do_not_hide_reserved_names() {
+        let rules = NameRules {
+            reserved: vec!["admin".to_string()],
+            ..NameRules::default()
+        };
+        assert!(rules.check("Admin\u{200b}").is_err());
+        assert!(rules.check("\u{202e}nimda").is_err());
+        assert!(rules.check("Ad\u{2060}min").is_err());
+    }
+
+    #[test]
+    fn reserved_names_ignore_case_and_normalization() {
+        let rules = NameRules {
+            normalization: Normalization::Nfkc,
+            reserved: vec!["ＡＤＭＩＮ".to_string(), " Café ".to_string()],
+            ..NameRules::default()
+        };
+        assert!(rules.check("admin").is_err());
+        assert!(rules.check("Admin").is_err());
+        assert!(rules.check("CAFE\u{301}").is_err());
+        assert!(rules.check("admins").is_ok());
+    }
+
+    #[test]
+    fn classes_parse_from_their_names() {
+        for class in CharacterClass::ALL {
+            let name = format!("{class:?}").to_lowercase();
+            assert_eq!(name.parse::<CharacterClass>(), Ok(class));
+        }
+        assert!("emoji".parse::<CharacterClass>().is_err());
+        assert_eq!("nfkc".parse::<Normalization>(), Ok(Normalization::Nfkc));
+    }
+}
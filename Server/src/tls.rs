@@ -0,0 +1,383 @@
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use tokio_rustls::TlsAcceptor;
+use warp::{Filter, Rejection, Reply};
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+/// Time a connection has to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, like when the process has no file descriptor left,
+/// so the error is not retried in a busy loop
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// PEM files of the certificate chain and its private key
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Address of the peer of a TLS connection.
+/// warp only knows the address of the connections it accepts itself.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeerAddress(pub IpAddr);
+
+/// Gives the last certificate loaded to every new connection
+struct CertificateResolver {
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certified_key = self
+            .certified_key
+            .read()
+            .unwrap_or_else(|error| error.into_inner());
+        Some(certified_key.clone())
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load_certified_key(tls: &TlsOptions) -> io::Result<CertifiedKey> {
+    let mut certificate = BufReader::new(File::open(&tls.certificate)?);
+    let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut certificate)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certificates.is_empty() {
+        let path = tls.certificate.display();
+        return Err(invalid_data(format!("no certificate found in '{path}'")));
+    }
+
+    let mut key = BufReader::new(File::open(&tls.key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => {
+                let path = tls.key.display();
+                return Err(invalid_data(format!("no private key found in '{path}'")));
+            }
+        }
+    };
+
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|error| invalid_data(format!("unsupported private key: {error}")))?;
+    if !key_matches(&certificates[0], &*signing_key) {
+        let (certificate, key) = (tls.certificate.display(), tls.key.display());
+        let message =
+            format!("the private key in '{key}' does not match the certificate in '{certificate}'");
+        return Err(invalid_data(message));
+    }
+
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+/// rustls does not check that the key belongs to the certificate, the handshakes
+/// would fail. So a message signed with the key is checked with the certificate
+fn key_matches(certificate: &Certificate, signing_key: &dyn sign::SigningKey) -> bool {
+    const MESSAGE: &[u8] = b"rtc-lobby certificate check";
+    let algorithms = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+    ];
+    let schemes: Vec<SignatureScheme> = algorithms.iter().map(|(scheme, _)| *scheme).collect();
+
+    let Some(signer) = signing_key.choose_scheme(&schemes) else {
+        return false;
+    };
+    let Some((_, algorithm)) = algorithms
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    else {
+        return false;
+    };
+    let Ok(signature) = signer.sign(MESSAGE) else {
+        return false;
+    };
+    webpki::EndEntityCert::try_from(certificate.0.as_slice())
+        .and_then(|certificate| certificate.verify_signature(algorithm, MESSAGE, &signature))
+        .is_ok()
+}
+
+impl CertificateResolver {
+    fn new(tls: &TlsOptions) -> io::Result<CertificateResolver> {
+        Ok(CertificateResolver {
+            certified_key: RwLock::new(Arc::new(load_certified_key(tls)?)),
+        })
+    }
+
+    /// Loads the certificate again if one of its files changed since `last_modified`.
+    /// If the new files are invalid, the previous certificate is kept.
+    fn reload_if_modified(&self, tls: &TlsOptions, last_modified: &mut Modified) {
+        let now_modified = (modified(&tls.certificate), modified(&tls.key));
+        if now_modified == *last_modified {
+            return;
+        }
+        *last_modified = now_modified;
+
+        match load_certified_key(tls) {
+            Ok(certified_key) => {
+                tracing::info!("Reloaded the TLS certificate");
+                *self
+                    .certified_key
+                    .write()
+                    .unwrap_or_else(|error| error.into_inner()) = Arc::new(certified_key);
+            }
+            Err(error) => tracing::error!(%error, "Could not reload the TLS certificate"),
+        }
+    }
+}
+
+/// Modification times of the certificate and key files
+type Modified = (Option<SystemTime>, Option<SystemTime>);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Loads the certificate again each time one of its files changes
+async fn reload_certificate(tls: TlsOptions, resolver: Arc<CertificateResolver>) {
+    let mut last_modified = (modified(&tls.certificate), modified(&tls.key));
+    let mut interval = time::interval(RELOAD_CHECK_PERIOD);
+
+    loop {
+        interval.tick().await;
+        resolver.reload_if_modified(&tls, &mut last_modified);
+    }
+}
+
+/// Serves the routes over HTTPS and WSS.
+/// Only fails if the certificate can not be loaded or the address can not be bound.
+pub async fn serve_tls<F, R>(routes: F, address: SocketAddr, tls: TlsOptions) -> io::Result<()>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let resolver = Arc::new(CertificateResolver::new(&tls)?);
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    // WebSockets need HTTP/1.1 upgrades
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(address).await?;
+    tokio::spawn(reload_certificate(tls, resolver));
+
+    let service = warp::service(routes);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                tracing::error!(%error, "Could not accept a connection");
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let Ok(Ok(stream)) = time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+            else {
+                return;
+            };
+
+            let service = service_fn(move |mut request| {
+                request.extensions_mut().insert(PeerAddress(peer.ip()));
+                service.clone().call(request)
+            });
+
+            let _ = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routes;
+    use crate::server::Server;
+    use std::time::UNIX_EPOCH;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    /// Certificates signed by `ca.pem` for `localhost`
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    fn options(certificate: &str, key: &str) -> TlsOptions {
+        TlsOptions {
+            certificate: fixture(certificate),
+            key: fixture(key),
+        }
+    }
+
+    fn certificate_der(name: &str) -> Vec<u8> {
+        let mut file = BufReader::new(File::open(fixture(name)).unwrap());
+        rustls_pemfile::certs(&mut file).unwrap().remove(0)
+    }
+
+    fn load_error(tls: &TlsOptions) -> io::Error {
+        match load_certified_key(tls) {
+            Ok(_) => panic!("the certificate should not load"),
+            Err(error) => error,
+        }
+    }
+
+    /// Writes the file and gives it a modification time of `seconds`
+    fn write(path: &Path, content: &[u8], seconds: u64) {
+        std::fs::write(path, content).unwrap();
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn certificates_load_with_their_key() {
+        let certified_key = load_certified_key(&options("certificate.pem", "key.pem")).unwrap();
+        assert_eq!(
+            certified_key.cert,
+            [Certificate(certificate_der("certificate.pem"))]
+        );
+    }
+
+    #[test]
+    fn invalid_certificate_files_are_refused() {
+        let error = load_error(&options("certificate.pem", "missing.pem"));
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        let error = load_error(&options("certificate.pem", "certificate.pem"));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("no private key"));
+
+        let error = load_error(&options("key.pem", "key.pem"));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("no certificate"));
+
+        let error = load_error(&options("certificate.pem", "other_key.pem"));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn reloads_keep_the_last_valid_certificate() {
+        let directory = std::env::temp_dir().join(format!("rtc-lobby-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let tls = TlsOptions {
+            certificate: directory.join("certificate.pem"),
+            key: directory.join("key.pem"),
+        };
+        write(
+            &tls.certificate,
+            &std::fs::read(fixture("certificate.pem")).unwrap(),
+            1,
+        );
+        write(&tls.key, &std::fs::read(fixture("key.pem")).unwrap(), 1);
+
+        let resolver = CertificateResolver::new(&tls).unwrap();
+        let mut last_modified = (modified(&tls.certificate), modified(&tls.key));
+        let certificate = || resolver.certified_key.read().unwrap().cert[0].0.clone();
+
+        // The new certificate does not match the key yet
+        let other_certificate = std::fs::read(fixture("other_certificate.pem")).unwrap();
+        write(&tls.certificate, &other_certificate, 2);
+        resolver.reload_if_modified(&tls, &mut last_modified);
+        assert_eq!(certificate(), certificate_der("certificate.pem"));
+
+        write(
+            &tls.key,
+            &std::fs::read(fixture("other_key.pem")).unwrap(),
+            3,
+        );
+        resolver.reload_if_modified(&tls, &mut last_modified);
+        assert_eq!(certificate(), certificate_der("other_certificate.pem"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_are_served_over_tls() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = Arc::new(Server::default());
+        let tls = options("certificate.pem", "key.pem");
+        tokio::spawn(serve_tls(routes(server), address, tls));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(certificate_der("ca.pem"))).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(connected) = TcpStream::connect(address).await {
+                stream = Some(connected);
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream.expect("the server should listen"))
+            .await
+            .unwrap();
+
+        let request = "GET /healthz HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+    }
+}
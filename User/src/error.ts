@@ -29,6 +29,27 @@ export interface LobbyNotFound {
     errorType: "lobbyNotFound";
 }
 
+export interface LobbyFull {
+    error: string;
+    errorType: "lobbyFull";
+}
+
+export interface WrongPassword {
+    error: string;
+    errorType: "wrongPassword";
+}
+
+export interface RateLimited {
+    error: string;
+    errorType: "rateLimited";
+}
+
+export interface JoinRejected {
+    error: string;
+    errorType: "joinRejected";
+    code?: string;
+}
+
 export interface LobbyAlreadyExists {
     error: string;
     errorType: "lobbyAlreadyExists";
@@ -3,9 +3,9 @@ import wrtc from "wrtc";
 globalThis.RTCPeerConnection =  wrtc.RTCPeerConnection;
 */
 
-import { InternalError, LobbyNotFound } from "./error.js";
+import { InternalError, InvalidData, JoinRejected, LobbyFull, LobbyNotFound, RateLimited, TimeoutError, WrongPassword } from "./error.js";
 import { createLinkResponse, createLinkRequest, RTCOffer, RTCLink } from "./rtc-link.js";
-import { connectClient, createServerHostConnection, serverRequest } from "./signaling.js";
+import { connectClient, createServerHostConnection, createServerStandbyConnection, JoinRejection, ServerHost, serverRequest } from "./signaling.js";
 
 
 export type ClientId = number;
@@ -19,17 +19,28 @@ export interface LobbyDetails {
     publicLobby: boolean;
     maxClients: number;
     clientCount: number;
+    hasPassword: boolean;
+    /** Set by the host, like the game mode or the map */
+    metadata: Record<string, string>;
 }
 
 export interface LobbyCreationOptions {
     lobbyName?: string;
     publicLobby?: boolean;
     maxClients?: number;
+    /** Lets the host reclaim the lobby name for a while after disconnecting */
+    reservationKey?: string;
+    /** Clients have to know it to join */
+    password?: string;
+    metadata?: Record<string, string>;
 }
 
 export interface LobbyHost extends Readonly<LobbyDetails> {
     onClientConnect?: (client: ClientConnection) => void;
 
+    /** Return a rejection to refuse a client before connecting with it */
+    onJoinRequest?: () => JoinRejection | undefined;
+
     /** 
      * If lobby closes, no more clients will be able to join.
      * However, the current rtc-links with the clients will remain.
@@ -38,6 +49,21 @@ export interface LobbyHost extends Readonly<LobbyDetails> {
     */
     onClose?: () => void;
 
+    /**
+     * The signaling server is stopping. Join requests already sent to the host
+     * can still be answered during the grace period, then the lobby closes.
+     */
+    onServerShutdown?: (gracePeriodMs: number) => void;
+
+    /**
+     * Picks the standby host that takes the lobby when this host leaves.
+     * Without id, the server picks the one that stood by first.
+     * Resolves to true once the server has recorded it.
+     */
+    designateSuccessor(
+        candidateId?: number, timeoutMs?: number
+    ): Promise<true | TimeoutError | InvalidData>;
+
     // It will close the connection with the signaling server
     // The client links will remain active
     close(): void;
@@ -48,14 +74,20 @@ export async function createLobby(
     lobbyDetails: LobbyCreationOptions = {},
     timeoutMs: number = 5000,
 ): Promise<LobbyHost | InternalError> {
-    let lastClientId: ClientId = 0;
-    const createClientId = (): ClientId => lastClientId++;
-
     const server = await createServerHostConnection(serverURL, lobbyDetails, timeoutMs);
     if ("error" in server) return server;
 
+    return hostLobby(server);
+}
+
+function hostLobby(server: ServerHost): LobbyHost {
+    let lastClientId: ClientId = 0;
+    const createClientId = (): ClientId => lastClientId++;
+
     const lobby: LobbyHost = {
         ...server.lobbyDetails,
+        designateSuccessor: (candidateId?: number, timeoutMs: number = 5000) =>
+            server.designateSuccessor(candidateId, timeoutMs),
         close: () => server.close(),
     };
 
@@ -75,13 +107,53 @@ export async function createLobby(
         return linkResponse.answer;
     };
 
+    server.rejectJoin = () => lobby.onJoinRequest?.();
+
     server.onClose = () => {
         lobby.onClose?.();
     };
 
+    server.onServerShutdown = (gracePeriodMs: number) => {
+        lobby.onServerShutdown?.(gracePeriodMs);
+    };
+
     return lobby;
 }
 
+export interface LobbyStandby {
+    /** The host of the lobby designates this standby host as its successor with it */
+    readonly candidateId: number;
+
+    /**
+     * The host left and this standby host took the lobby.
+     * The clients of the previous host are not connected to it.
+     */
+    onPromotion?: (lobby: LobbyHost) => void;
+
+    // Stops standing by
+    close(): void;
+}
+
+/** Stands by to take the lobby, with its name and details, when its host leaves */
+export async function standbyLobby(
+    serverURL: string,
+    lobbyName: string,
+    password?: string,
+    timeoutMs: number = 5000,
+): Promise<LobbyStandby | InternalError | LobbyNotFound | WrongPassword> {
+    const server = await createServerStandbyConnection(serverURL, lobbyName, password, timeoutMs);
+    if ("error" in server) return server;
+
+    const standby: LobbyStandby = {
+        candidateId: server.candidateId,
+        close: () => server.close(),
+    };
+
+    server.promotion.then(host => standby.onPromotion?.(hostLobby(host)));
+
+    return standby;
+}
+
 export interface LobbyClient extends RTCLink { }
 
 /** If lobbyName is undefined, the server will choose a random public lobby */
@@ -89,11 +161,12 @@ export async function joinLobby(
     serverURL: string,
     lobbyName?: string,
     timeoutMs: number = 5000,
-): Promise<LobbyClient | InternalError | LobbyNotFound> {
+    password?: string,
+): Promise<LobbyClient | InternalError | LobbyNotFound | LobbyFull | JoinRejected | WrongPassword | RateLimited> {
     const linkRequest = await createLinkRequest();
     if ("error" in linkRequest) return linkRequest;
 
-    const answer = await connectClient(serverURL, linkRequest.offer, timeoutMs, lobbyName);
+    const answer = await connectClient(serverURL, linkRequest.offer, timeoutMs, lobbyName, password);
     if (typeof answer != "string") {
         linkRequest.close();
         return answer;
@@ -105,9 +178,36 @@ export async function joinLobby(
     return link;
 }
 
+/**
+ * Lobbies without the key never match. With `min` or `max` the value has to be
+ * a number in the range, bounds included.
+ */
+export interface LobbyFilter {
+    key: string,
+    equals?: string,
+    min?: number,
+    max?: number,
+}
+
+/** Ties are sorted by name. "capacity" counts the clients that are still joining */
+export type LobbySort = "name" | "capacity" | "clientCount" | "creationTime";
+
 interface ListLobbiesOptions {
     maximumLobbies?: number,
     minimumCapacity?: number,
+    /** Only lobbies matching every filter are listed */
+    filters?: LobbyFilter[],
+    /** Oldest lobbies first by default */
+    sort?: LobbySort,
+    descending?: boolean,
+}
+
+export interface LobbiesPage {
+    lobbies: LobbyDetails[],
+    /** Lobbies matching the options in every page */
+    total: number,
+    /** Lists the next page, if there is one */
+    nextCursor?: string,
 }
 
 export async function listPublicLobbies(
@@ -115,14 +215,37 @@ export async function listPublicLobbies(
     options: ListLobbiesOptions = {},
     timeoutMs: number = 5000,
 ): Promise<LobbyDetails[] | InternalError> {
+    const page = await listPublicLobbiesPage(serverURL, options, undefined, timeoutMs);
+    if ("error" in page) return page;
+    return page.lobbies;
+}
+
+/**
+ * Lists the lobbies after the cursor of the previous page. Lobbies created or
+ * closed between two requests don't shift the pages.
+ */
+export async function listPublicLobbiesPage(
+    serverURL: string,
+    options: ListLobbiesOptions = {},
+    cursor?: string,
+    timeoutMs: number = 5000,
+): Promise<LobbiesPage | InternalError> {
     const response = await serverRequest(serverURL, timeoutMs, {
         type: "lobbies-list-request",
         maximumLobbies: options.maximumLobbies ?? 1000,
         minimumCapacity: options.minimumCapacity ?? 0,
+        filters: options.filters,
+        sort: options.sort,
+        descending: options.descending,
+        cursor,
     });
     
     if ("error" in response) return response;
-    if (response.type == "lobbies-list") return response.lobbies;
+    if (response.type == "lobbies-list") return {
+        lobbies: response.lobbies,
+        total: response.total,
+        nextCursor: response.nextCursor ?? undefined,
+    };
 
     return {
         error: `The server returned unexpected data (Data received: ${JSON.stringify(response)})`,
@@ -1,45 +1,131 @@
-import { createLobby, LobbyCreationOptions, LobbyDetails } from "./index.js";
-import { InternalError, createTimeout, DeserializeError, displayAny, ConnectionError, SerializeError, TimeoutError, LobbyNotFound } from "./error.js";
+import { createLobby, LobbyCreationOptions, LobbyDetails, LobbyFilter, LobbySort } from "./index.js";
+import { InternalError, createTimeout, DeserializeError, displayAny, ConnectionError, SerializeError, TimeoutError, LobbyNotFound, LobbyFull, JoinRejected, WrongPassword, RateLimited, InvalidData } from "./error.js";
 import { RTCAnswer, RTCOffer } from "./rtc-link.js";
 import { DeserializeResult, parse, stringify } from "./serializer.js";
 
 //! if target == NodeJs
 import WebSocket from "ws";
 
+/** Version of the protocol spoken with the server, sent in a "hello" */
+const PROTOCOL_VERSION = 2;
+const PROTOCOL_FEATURES = ["resume", "migration", "shutdown"];
+
+/**
+ * Messages of the host may have a `requestId`, which the server sends back
+ * in the message or error that answers it
+ */
 export type MsgSchema =
+    {
+        type: "hello",
+        version: number,
+        features?: string[],
+        requestId?: string,
+    } |
     {
         type: "create-lobby",
         lobbyName?: string;
         publicLobby: boolean;
         maxClients?: number;
+        reservationKey?: string;
+        password?: string;
+        metadata?: Record<string, string>;
+        requestId?: string,
     } |
     {
         type: "join-request",
         lobbyName?: string,
         offer: RTCOffer,
         id?: number,
+        password?: string,
     } |
     {
         type: "join-invitation",
         answer: RTCAnswer,
         id?: number,
+        requestId?: string,
+    } |
+    {
+        type: "join-rejection",
+        reason: string,
+        code?: string,
+        id?: number,
+        requestId?: string,
+    } |
+    {
+        type: "ice-candidate",
+        candidate: string,
+        id?: number,
+        requestId?: string,
+    } |
+    {
+        type: "join-expired",
+        id: number,
+        requestId?: string,
     } |
     {
         type: "lobby-details",
         details: LobbyDetails,
+        requestId?: string,
     } |
     {
         type: "lobbies-list-request",
         maximumLobbies: number,
         minimumCapacity: number,
+        filters?: LobbyFilter[],
+        sort?: LobbySort,
+        descending?: boolean,
+        cursor?: string,
     } |
     {
         type: "lobbies-list"
         lobbies: LobbyDetails[],
+        total: number,
+        nextCursor: string | null,
+    } |
+    {
+        type: "resume-token",
+        resumeToken: string,
+    } |
+    {
+        type: "resume-lobby",
+        lobbyName: string,
+        resumeToken: string,
+        requestId?: string,
+    } |
+    {
+        type: "standby-host",
+        lobbyName: string,
+        password?: string,
+        requestId?: string,
+    } |
+    {
+        type: "standby-accepted",
+        candidateId: number,
+        requestId?: string,
+    } |
+    {
+        type: "designate-successor",
+        candidateId?: number,
+        requestId?: string,
+    } |
+    {
+        /** Answers a message that has no other reply */
+        type: "ack",
+        requestId: string,
+    } |
+    {
+        type: "server-shutdown",
+        /** Seconds the pending joins can still be answered */
+        gracePeriod: number,
     } |
     {
         type: "error",
-        errorType: "lobbyNotFound" | "lobbyAlreadyExists" | "invalidMessage",
+        errorType: "lobbyNotFound" | "lobbyAlreadyExists" | "invalidMessage" | "tooManyLobbies" | "lobbyFull" | "joinTimeout" | "wrongPassword" | "rateLimited" | "serverShuttingDown" | "metadataTooLarge" | "unsupportedVersion"
+            | "malformedJson" | "unknownType" | "invalidField" | "internal",
+        message?: string,
+        /** The field of the message that is wrong */
+        field?: string,
+        requestId?: string,
     };
 
 
@@ -86,7 +172,11 @@ function createWebSocket(url: string | URL, timeoutMs: number) {
         },
     };
 
-    ws.onopen = () => timeout.resolve(server);
+    ws.onopen = () => {
+        // The server answers with the version and the features it agrees on
+        server.send({ type: "hello", version: PROTOCOL_VERSION, features: PROTOCOL_FEATURES });
+        timeout.resolve(server);
+    };
 
     return timeout.result;
 }
@@ -96,17 +186,54 @@ export interface UpdateLobbyDetails {
     publicLobby?: boolean;
     maxClients?: number;
     clientCount?: number;
+    metadata?: Record<string, string>;
 };
 
-interface ServerHost {
+export interface JoinRejection {
+    reason: string;
+    code?: string;
+}
+
+export interface ServerHost {
     lobbyDetails: LobbyDetails;
+    /** Return a rejection to refuse the client */
+    rejectJoin?: () => JoinRejection | undefined,
     createRTCAnswer?: (offer: RTCOffer) => Promise<RTCAnswer | InternalError>,
     /** If the timeout expires the update could happend later anyway */
     updateLobbyDetails(
         details: UpdateLobbyDetails, timeoutMs: number
-    ): Promise<LobbyDetails | TimeoutError>;
+    ): Promise<LobbyDetails | TimeoutError | InvalidData>;
+    /** Without id, the server picks the candidate that stood by first */
+    designateSuccessor(
+        candidateId: number | undefined, timeoutMs: number
+    ): Promise<true | TimeoutError | InvalidData>;
     close(): void;
     onClose?: () => void;
+    onServerShutdown?: (gracePeriodMs: number) => void;
+}
+
+interface ServerStandby {
+    candidateId: number;
+    /** Resolves when the host leaves and this connection takes the lobby */
+    promotion: Promise<ServerHost>;
+    close(): void;
+}
+
+/**
+ * The server URL may start with a scheme ("https://", "wss://", ...) to choose
+ * between secure and plain connections. Without one, secure connections are
+ * used when the page itself was loaded over HTTPS.
+ */
+function endpointURL(serverURL: string, protocol: "ws" | "http", path: string): string {
+    let secure = (globalThis as any).location?.protocol == "https:";
+
+    const scheme = /^(wss?|https?):\/\//.exec(serverURL);
+    if (scheme) {
+        secure = scheme[1].endsWith("s");
+        serverURL = serverURL.slice(scheme[0].length);
+    }
+
+    return `${protocol}${secure ? "s" : ""}://${serverURL}${path}`;
 }
 
 export async function createServerHostConnection(
@@ -114,7 +241,7 @@ export async function createServerHostConnection(
     lobbyOpts: LobbyCreationOptions,
     timeoutMs: number,
 ): Promise<ServerHost | InternalError> {
-    serverURL = "ws://" + serverURL + "/api/host";
+    serverURL = endpointURL(serverURL, "ws", "/api/host");
     const channel = await createWebSocket(serverURL, timeoutMs);
     if ("error" in channel) return channel;
 
@@ -139,13 +266,21 @@ export async function createServerHostConnection(
         };
     }
 
-    const timeoutHandle = createTimeout<{ details: LobbyDetails } | DeserializeError>(
+    const timeoutHandle = createTimeout<{ details: LobbyDetails } | DeserializeError | ConnectionError | InvalidData>(
         timeoutMs, "The server has not answered the 'create-lobby' request"
     );
 
+    let resumeToken: string | undefined;
+
     channel.onReceive = async message => {
         if ("error" in message) timeoutHandle.resolve(message);
-        else if (message.data.type == "lobby-details") {
+        else if (message.data.type == "error" && message.data.errorType == "unsupportedVersion") {
+            timeoutHandle.resolve({ error: "The server does not speak this version of the protocol", errorType: "connection" });
+        } else if (message.data.type == "error" && message.data.errorType == "invalidField") {
+            timeoutHandle.resolve({ error: message.data.message ?? "The lobby creation options are invalid", errorType: "invalidData" });
+        } else if (message.data.type == "resume-token") {
+            resumeToken = message.data.resumeToken;
+        } else if (message.data.type == "lobby-details") {
             timeoutHandle.resolve({ details: message.data.details });
         }
     };
@@ -156,7 +291,80 @@ export async function createServerHostConnection(
         return lobby;
     }
 
-    let onLobbyDetails: ((details: LobbyDetails) => void)[] = [];
+    return hostLobby(serverURL, channel, lobby.details, resumeToken, timeoutMs);
+}
+
+/** Stands by to become the host of the lobby when its host leaves */
+export async function createServerStandbyConnection(
+    serverURL: string,
+    lobbyName: string,
+    password: string | undefined,
+    timeoutMs: number,
+): Promise<ServerStandby | InternalError | LobbyNotFound | WrongPassword> {
+    serverURL = endpointURL(serverURL, "ws", "/api/host");
+    const channel = await createWebSocket(serverURL, timeoutMs);
+    if ("error" in channel) return channel;
+
+    channel.send({ type: "standby-host", lobbyName: String(lobbyName), password });
+
+    const timeoutHandle = createTimeout<{ candidateId: number } | DeserializeError | LobbyNotFound | WrongPassword | InternalError>(
+        timeoutMs, "The server has not answered the 'standby-host' request"
+    );
+
+    let resumeToken: string | undefined;
+    let promote!: (server: ServerHost) => void;
+    const promotion = new Promise<ServerHost>(resolve => promote = resolve);
+
+    channel.onReceive = async message => {
+        if ("error" in message) return timeoutHandle.resolve(message);
+
+        const data = message.data;
+        if (data.type == "standby-accepted") {
+            timeoutHandle.resolve({ candidateId: data.candidateId });
+        } else if (data.type == "resume-token") {
+            resumeToken = data.resumeToken;
+        } else if (data.type == "lobby-details") {
+            // The pending join requests follow the details, so the lobby is held right away
+            promote(hostLobby(serverURL, channel, data.details, resumeToken, timeoutMs));
+        } else if (data.type == "error" && data.errorType == "lobbyNotFound") {
+            timeoutHandle.resolve({ error: `There is no lobby with name '${lobbyName}'`, errorType: "lobbyNotFound" });
+        } else if (data.type == "error" && data.errorType == "wrongPassword") {
+            timeoutHandle.resolve({ error: `Wrong password for lobby '${lobbyName}'`, errorType: "wrongPassword" });
+        } else if (data.type == "error") {
+            timeoutHandle.resolve({ error: `The server refused to stand by (${data.message ?? data.errorType})`, errorType: "connection" });
+        }
+    };
+
+    const accepted = await timeoutHandle.result;
+    if ("error" in accepted) {
+        channel.close();
+        return accepted;
+    }
+
+    return {
+        candidateId: accepted.candidateId,
+        promotion,
+        close: () => channel.close(),
+    };
+}
+
+/** Holds a lobby once the server gave it to the connection */
+function hostLobby(
+    serverURL: string,
+    connection: ServerChannel,
+    details: LobbyDetails,
+    resumeToken: string | undefined,
+    timeoutMs: number,
+): ServerHost {
+    const lobby = { details };
+    // Replaced when the lobby is resumed on a new connection
+    let channel = connection;
+    // Updates waiting for an answer, by request id
+    const pendingUpdates = new Map<string, (result: LobbyDetails | InvalidData) => void>();
+    // Messages waiting for an ack, by request id
+    const pendingAcks = new Map<string, (result: true | InvalidData) => void>();
+    let lastRequestId = 0;
+    let closed = false;
 
     const server: ServerHost = {
         lobbyDetails: {
@@ -164,29 +372,48 @@ export async function createServerHostConnection(
             get publicLobby() { return lobby.details.publicLobby; },
             get maxClients() { return lobby.details.maxClients; },
             get clientCount() { return lobby.details.clientCount; },
+            get hasPassword() { return lobby.details.hasPassword; },
+            get metadata() { return lobby.details.metadata; },
         },
         updateLobbyDetails: (details: UpdateLobbyDetails, timeoutMs: number) => {
+            const requestId = String(++lastRequestId);
             channel.send({
                 type: "lobby-details",
                 details: {
                     ...lobby.details,
                     ...details,
-                }
+                },
+                requestId,
             });
-            const timeoutHandle = createTimeout<LobbyDetails>(timeoutMs, "The server has not answered");
-            onLobbyDetails.push(timeoutHandle.resolve);
+            const timeoutHandle = createTimeout<LobbyDetails | InvalidData>(timeoutMs, "The server has not answered");
+            pendingUpdates.set(requestId, timeoutHandle.resolve);
+            timeoutHandle.result.then(() => pendingUpdates.delete(requestId));
             return timeoutHandle.result;
         },
-        close: () => channel.close(),
+        designateSuccessor: (candidateId: number | undefined, timeoutMs: number) => {
+            const requestId = String(++lastRequestId);
+            channel.send({ type: "designate-successor", candidateId, requestId });
+            const timeoutHandle = createTimeout<true | InvalidData>(timeoutMs, "The server has not answered");
+            pendingAcks.set(requestId, timeoutHandle.resolve);
+            timeoutHandle.result.then(() => pendingAcks.delete(requestId));
+            return timeoutHandle.result;
+        },
+        close: () => {
+            closed = true;
+            channel.close();
+        },
     };
 
-    channel.onReceive = async received => {
+    const onReceive = async (received: DeserializeResult<MsgSchema>) => {
         // Ignore invalid messages
         if ("error" in received) return;
 
         const message = received.data;
         if (message.type == "join-request") {
-            if (server.createRTCAnswer) {
+            const rejection = server.rejectJoin?.();
+            if (rejection) {
+                channel.send({ type: "join-rejection", ...rejection, id: message.id });
+            } else if (server.createRTCAnswer) {
                 const answer = await server.createRTCAnswer(message.offer);
                 if (typeof answer == "string") {
                     channel.send({ type: "join-invitation", answer, id: message.id });
@@ -194,17 +421,69 @@ export async function createServerHostConnection(
             }
         } else if (message.type == "lobby-details") {
             lobby.details = message.details;
-            for (const callback of onLobbyDetails) {
-                callback(server.lobbyDetails);
+            if (message.requestId) pendingUpdates.get(message.requestId)?.(server.lobbyDetails);
+        } else if (message.type == "ack") {
+            pendingAcks.get(message.requestId)?.(true);
+        } else if (message.type == "error" && message.requestId) {
+            pendingUpdates.get(message.requestId)?.({
+                error: `The server refused the update (${message.message ?? message.errorType})`,
+                errorType: "invalidData",
+            });
+            pendingAcks.get(message.requestId)?.({
+                error: `The server refused the request (${message.message ?? message.errorType})`,
+                errorType: "invalidData",
+            });
+        } else if (message.type == "resume-token") {
+            resumeToken = message.resumeToken;
+        } else if (message.type == "server-shutdown") {
+            server.onServerShutdown?.(message.gracePeriod * 1000);
+        }
+    };
+
+    // Try once to take the lobby back when the connection drops
+    const resume = async () => {
+        const token = resumeToken;
+        if (closed || !token) return false;
+
+        const resumed = await createWebSocket(serverURL, timeoutMs);
+        if ("error" in resumed) return false;
+
+        const timeoutHandle = createTimeout<boolean>(
+            timeoutMs, "The server has not answered the 'resume-lobby' request"
+        );
+        resumed.onReceive = async message => {
+            if ("error" in message) return;
+            if (message.data.type == "lobby-details") {
+                lobby.details = message.data.details;
+                // The pending join requests follow the details
+                resumed.onReceive = onReceive;
+                timeoutHandle.resolve(true);
+            } else if (message.data.type == "error") {
+                timeoutHandle.resolve(false);
             }
-            onLobbyDetails = [];
+        };
+        resumed.onClose = () => timeoutHandle.resolve(false);
+        resumed.send({ type: "resume-lobby", lobbyName: lobby.details.lobbyName, resumeToken: token });
+
+        const result = await timeoutHandle.result;
+        if (result !== true || closed) {
+            resumed.close();
+            return false;
         }
+
+        channel = resumed;
+        channel.onClose = onClose;
+        return true;
     };
 
-    channel.onClose = () => {
+    const onClose = async () => {
+        if (await resume()) return;
         server.onClose?.();
     };
 
+    channel.onReceive = onReceive;
+    channel.onClose = onClose;
+
     return server;
 }
 
@@ -213,7 +492,7 @@ export async function serverRequest(
     timeoutMs: number,
     request: MsgSchema,
 ): Promise<MsgSchema | InternalError> {
-    serverURL = "http://" + serverURL + "/api/client";
+    serverURL = endpointURL(serverURL, "http", "/api/client");
 
     const strRequest = stringify(request);
     if (typeof strRequest != "string") return strRequest;
@@ -232,7 +511,8 @@ export async function serverRequest(
             method: "POST",
             headers: {
                 'Accept': 'application/json',
-                'Content-Type': 'application/json'
+                'Content-Type': 'application/json',
+                'RTC-Lobby-Protocol': String(PROTOCOL_VERSION),
             },
             body: strRequest,
             signal: controller.signal,
@@ -270,7 +550,8 @@ export async function connectClient(
     offer: RTCOffer,
     timeoutMs: number,
     lobbyName?: string,
-): Promise<RTCAnswer | InternalError | LobbyNotFound> {
+    password?: string,
+): Promise<RTCAnswer | InternalError | LobbyNotFound | LobbyFull | JoinRejected | WrongPassword | RateLimited> {
     /*
         const joinRequest = stringify<MsgSchema>({
             type: "join-request",
@@ -327,17 +608,36 @@ export async function connectClient(
         type: "join-request",
         lobbyName,
         offer,
+        password,
     });
 
     if ("error" in message) return message;
 
     if (message.type == "join-invitation") return message.answer;
+    if (message.type == "join-rejection") {
+        return { error: message.reason, errorType: "joinRejected", code: message.code ?? undefined };
+    }
     if (message.type == "error" && message.errorType == "lobbyNotFound") {
         let error: string;
         if (lobbyName) error = `There is no lobby with name '${lobbyName}'`;
         else error = "There wasn't any public lobby to join";
         return { error, errorType: "lobbyNotFound" };
     }
+    if (message.type == "error" && message.errorType == "joinTimeout") {
+        return { error: "The host has not answered the join request", errorType: "timeout" };
+    }
+    if (message.type == "error" && message.errorType == "lobbyFull") {
+        return { error: `The lobby '${lobbyName}' is full`, errorType: "lobbyFull" };
+    }
+    if (message.type == "error" && message.errorType == "wrongPassword") {
+        return { error: `Wrong password for lobby '${lobbyName}'`, errorType: "wrongPassword" };
+    }
+    if (message.type == "error" && message.errorType == "rateLimited") {
+        return { error: "Too many requests to the server, try again later", errorType: "rateLimited" };
+    }
+    if (message.type == "error" && message.errorType == "serverShuttingDown") {
+        return { error: "The server is shutting down", errorType: "connection" };
+    }
 
     return {
         error: `The server returned unexpected data (Data received: ${JSON.stringify(message)})`,